@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use gorilla_tsdb::gorilla_tsz::codec::beringei;
+
+// The first byte is the number of points to decode, the rest is the stream
+fuzz_target!(|data: &[u8]| {
+    if let Some((&count, buf)) = data.split_first() {
+        let _ = beringei::decode_stream(buf, count as usize);
+    }
+});
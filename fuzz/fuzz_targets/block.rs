@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use gorilla_tsdb::gorilla_tsz::block::Block;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(block) = Block::from_bytes(data) {
+        for _ in block.measurements() {}
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use gorilla_tsdb::gorilla_tsz::codec::{decoder, explain};
+
+// The first byte is the number of measurements to decode, the rest is the block
+fuzz_target!(|data: &[u8]| {
+    if let Some((&count, buf)) = data.split_first() {
+        for _ in decoder::measurements(buf, count as usize) {}
+        explain::explain(buf, count as usize);
+    }
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use gorilla_tsdb::gorilla_tsz::codec::prometheus;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = prometheus::decode_chunk(data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use gorilla_tsdb::ingest::remote_write;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = remote_write::decode(data);
+    let _ = remote_write::decode_write_request(data);
+});
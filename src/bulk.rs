@@ -0,0 +1,510 @@
+// Bulk import and export of series data as CSV or NDJSON.
+//
+// Rows hold a series key in its text form (see SeriesKey's Display), a timestamp, a count
+// and a value. Which columns (or NDJSON fields) those come from is configurable, and further
+// columns can be added to the key as labels. Input is read a row at a time and measurements
+// go straight into per-series block encoders, which are handed off as soon as they fill, so
+// only the encoded data of unfinished blocks is kept in memory. Export decodes blocks
+// lazily in the same way.
+//
+// CSV fields may be quoted, but not span lines. Non-finite values are written as NaN, inf
+// and -inf in CSV and as "NaN", "Infinity" and "-Infinity" strings in NDJSON.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+use std::ops::Range;
+
+use serde_json::{json, Value};
+
+use crate::gorilla_tsz::Measurement;
+use crate::gorilla_tsz::block::{Block, BlockEncoder};
+use crate::gorilla_tsz::codec::decoder::DecoderError;
+use crate::gorilla_tsz::codec::encoder::EncoderError;
+use crate::ingest::{SeriesKey, Sample, merge_labels};
+
+// Measurements per imported block, unless told otherwise
+pub const DEFAULT_BLOCK_SIZE: usize = 10_000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    Csv,
+    Ndjson
+}
+
+// Column names to read each part of a row from. Rows without a count column get a count of 1.
+#[derive(Clone, Debug)]
+pub struct Mapping {
+    pub series: String,
+    pub labels: Vec<String>,
+    pub timestamp: String,
+    pub count: String,
+    pub value: String
+}
+
+impl Default for Mapping {
+    fn default() -> Self {
+        Mapping {
+            series: "series".to_string(),
+            labels: Vec::new(),
+            timestamp: "timestamp".to_string(),
+            count: "count".to_string(),
+            value: "value".to_string()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    MissingColumn(String),
+    // Line numbers start at 1
+    InvalidRow { line: usize, message: String },
+    EncoderError(EncoderError)
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Io(e) => write!(f, "{}", e),
+            ImportError::MissingColumn(column) => write!(f, "missing column: {}", column),
+            ImportError::InvalidRow { line, message } => write!(f, "line {}: {}", line, message),
+            ImportError::EncoderError(e) => write!(f, "encoding failed: {}", e)
+        }
+    }
+}
+
+impl error::Error for ImportError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ImportError::Io(e) => Some(e),
+            ImportError::EncoderError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+
+impl From<EncoderError> for ImportError {
+    fn from(e: EncoderError) -> Self {
+        ImportError::EncoderError(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(io::Error),
+    DecoderError(DecoderError)
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportError::Io(e) => write!(f, "{}", e),
+            ExportError::DecoderError(e) => write!(f, "decoding failed: {}", e)
+        }
+    }
+}
+
+impl error::Error for ExportError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ExportError::Io(e) => Some(e),
+            ExportError::DecoderError(e) => Some(e)
+        }
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(e: io::Error) -> Self {
+        ExportError::Io(e)
+    }
+}
+
+impl From<DecoderError> for ExportError {
+    fn from(e: DecoderError) -> Self {
+        ExportError::DecoderError(e)
+    }
+}
+
+// Parses a value as written by Display or in JSON
+pub fn parse_value(value: &str) -> Option<f64>
+{
+    match value {
+        "Infinity" => Some(f64::INFINITY),
+        "-Infinity" => Some(f64::NEG_INFINITY),
+        v => v.parse().ok()
+    }
+}
+
+// JSON has no non-finite numbers, so those are written as strings
+pub fn json_value(value: f64) -> Value
+{
+    if value.is_nan() {
+        json!("NaN")
+    } else if value.is_infinite() {
+        json!(if value > 0.0 { "Infinity" } else { "-Infinity" })
+    } else {
+        json!(value)
+    }
+}
+
+// Splits a CSV line into its fields, or None if a quote is left open
+pub(crate) fn split_csv(line: &str) -> Option<Vec<String>>
+{
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c)
+        }
+    }
+
+    if quoted {
+        return None;
+    }
+
+    fields.push(field);
+    Some(fields)
+}
+
+pub(crate) fn quote_csv(field: &str) -> String
+{
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Where each mapped column is in a CSV row
+struct Columns {
+    series: usize,
+    labels: Vec<(String, usize)>,
+    timestamp: usize,
+    count: Option<usize>,
+    value: usize
+}
+
+impl Columns {
+    fn new(header: &[String], mapping: &Mapping) -> Result<Columns, ImportError> {
+        let find = |name: &String| header.iter().position(|c| c == name);
+        let require = |name: &String| find(name).ok_or_else(|| ImportError::MissingColumn(name.clone()));
+
+        Ok(Columns {
+            series: require(&mapping.series)?,
+            labels: mapping.labels.iter().map(|l| require(l).map(|idx| (l.clone(), idx))).collect::<Result<_, _>>()?,
+            timestamp: require(&mapping.timestamp)?,
+            count: find(&mapping.count),
+            value: require(&mapping.value)?
+        })
+    }
+}
+
+fn sample(series: &str, labels: Vec<(String, String)>, timestamp: &str, count: Option<&str>, value: &str) -> Result<Sample, String>
+{
+    let key: SeriesKey = series.parse().map_err(|_| format!("invalid series key: {}", series))?;
+
+    // Label columns override labels of the same name in the series key
+    let mut all_labels = key.labels().to_vec();
+    merge_labels(&mut all_labels, labels);
+
+    let count = match count {
+        None | Some("") => 1,
+        Some(count) => count.parse().map_err(|_| format!("invalid count: {}", count))?
+    };
+
+    Ok(Sample {
+        key: SeriesKey::new(key.name().to_string(), all_labels),
+        measurement: Measurement {
+            timestamp: timestamp.parse().map_err(|_| format!("invalid timestamp: {}", timestamp))?,
+            count,
+            value: parse_value(value).ok_or_else(|| format!("invalid value: {}", value))?
+        }
+    })
+}
+
+fn csv_sample(line: &str, columns: &Columns) -> Result<Sample, String>
+{
+    let fields = split_csv(line).ok_or_else(|| "unterminated quote".to_string())?;
+    let field = |idx: usize| fields.get(idx).map(|f| f.trim()).ok_or_else(|| format!("missing column {}", idx + 1));
+
+    let labels = columns.labels.iter()
+        .map(|(name, idx)| field(*idx).map(|v| (name.clone(), v.to_string())))
+        .collect::<Result<_, _>>()?;
+
+    let count = match columns.count {
+        Some(idx) => Some(field(idx)?),
+        None => None
+    };
+
+    sample(field(columns.series)?, labels, field(columns.timestamp)?, count, field(columns.value)?)
+}
+
+fn ndjson_sample(line: &str, mapping: &Mapping) -> Result<Sample, String>
+{
+    let row: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+
+    // Numbers and strings are both accepted for any field
+    let field = |name: &String| match row.get(name) {
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(Value::Number(n)) => Ok(Some(n.to_string())),
+        None | Some(Value::Null) => Ok(None),
+        Some(_) => Err(format!("invalid {}", name))
+    };
+    let require = |name: &String| field(name)?.ok_or_else(|| format!("missing {}", name));
+
+    let labels = mapping.labels.iter()
+        .map(|name| require(name).map(|v| (name.clone(), v)))
+        .collect::<Result<_, _>>()?;
+
+    sample(&require(&mapping.series)?, labels, &require(&mapping.timestamp)?,
+           field(&mapping.count)?.as_deref(), &require(&mapping.value)?)
+}
+
+// Reads samples from CSV or NDJSON a line at a time
+pub struct Samples<R> {
+    lines: io::Lines<R>,
+    line: usize,
+    format: Format,
+    mapping: Mapping,
+    columns: Option<Columns>
+}
+
+impl<R: BufRead> Iterator for Samples<R> {
+    type Item = Result<Sample, ImportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(ImportError::Io(e)))
+            };
+            self.line += 1;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let result = match (&self.format, &self.columns) {
+                (Format::Csv, Some(columns)) => csv_sample(&line, columns),
+                (Format::Csv, None) => Err("missing header".to_string()),
+                (Format::Ndjson, _) => ndjson_sample(&line, &self.mapping)
+            };
+
+            return Some(result.map_err(|message| ImportError::InvalidRow { line: self.line, message }));
+        }
+    }
+}
+
+// Starts reading samples from `reader`. CSV input must start with a header naming its columns.
+pub fn samples<R: BufRead>(reader: R, format: Format, mapping: &Mapping) -> Result<Samples<R>, ImportError>
+{
+    let mut lines = reader.lines();
+    let mut columns = None;
+    let mut line = 0;
+
+    if format == Format::Csv {
+        if let Some(header) = lines.next() {
+            let header = split_csv(&header?).ok_or(ImportError::InvalidRow { line: 1, message: "unterminated quote".to_string() })?;
+            let header: Vec<String> = header.iter().map(|c| c.trim().to_string()).collect();
+
+            columns = Some(Columns::new(&header, mapping)?);
+            line = 1;
+        }
+    }
+
+    Ok(Samples { lines, line, format, mapping: mapping.clone(), columns })
+}
+
+// Encodes everything in `reader` into blocks of at most `block_size` measurements, handing
+// each to `sink` as soon as it's full. The unfinished blocks are handed over at the end, in
+// series order. A series' blocks always reach `sink` in order, and each series' rows should
+// be in timestamp order.
+pub fn import<R, F>(reader: R, format: Format, mapping: &Mapping, block_size: usize, mut sink: F) -> Result<(), ImportError>
+    where R: BufRead, F: FnMut(SeriesKey, Block) -> io::Result<()>
+{
+    let mut encoders: BTreeMap<SeriesKey, BlockEncoder> = BTreeMap::new();
+
+    for sample in samples(reader, format, mapping)? {
+        let sample = sample?;
+
+        if let Some(encoder) = encoders.get_mut(&sample.key) {
+            encoder.push(&sample.measurement)?;
+        } else {
+            let mut encoder = BlockEncoder::new();
+            encoder.push(&sample.measurement)?;
+            encoders.insert(sample.key.clone(), encoder);
+        }
+
+        if encoders[&sample.key].measurement_count() >= block_size {
+            if let Some((key, encoder)) = encoders.remove_entry(&sample.key) {
+                sink(key, encoder.finish())?;
+            }
+        }
+    }
+
+    for (key, encoder) in encoders {
+        sink(key, encoder.finish())?;
+    }
+
+    Ok(())
+}
+
+// Writes the measurements of every series in `series` with a timestamp in `range`. Blocks
+// are in timestamp order, so decoding stops at the end of the range.
+pub fn export<'a, W, I>(writer: &mut W, format: Format, series: I, range: Range<u64>) -> Result<(), ExportError>
+    where W: Write + ?Sized, I: IntoIterator<Item = (&'a SeriesKey, &'a Block)>
+{
+    if format == Format::Csv {
+        writeln!(writer, "series,timestamp,count,value")?;
+    }
+
+    for (key, block) in series {
+        let key = key.to_string();
+
+        for measurement in block.measurements() {
+            let m = measurement?;
+
+            if m.timestamp >= range.end {
+                break;
+            }
+
+            if m.timestamp < range.start {
+                continue;
+            }
+
+            match format {
+                Format::Csv => writeln!(writer, "{},{},{},{}", quote_csv(&key), m.timestamp, m.count, m.value)?,
+                Format::Ndjson => writeln!(writer, "{}",
+                                           json!({"series": key, "timestamp": m.timestamp, "count": m.count, "value": json_value(m.value)}))?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::ingest::testing::key;
+
+    fn measurements(block: &Block) -> Vec<Measurement> {
+        block.measurements().collect::<Result<_, _>>().unwrap()
+    }
+
+    fn import_all(input: &[u8], format: Format, mapping: &Mapping) -> Result<BTreeMap<SeriesKey, Block>, ImportError> {
+        let mut blocks = BTreeMap::new();
+
+        import(Cursor::new(input), format, mapping, DEFAULT_BLOCK_SIZE, |key, block| {
+            blocks.insert(key, block);
+            Ok(())
+        })?;
+        Ok(blocks)
+    }
+
+    #[test]
+    fn test_split_csv()
+    {
+        assert_eq!(split_csv("a,\"b,\"\"c\"\"\",,d"), Some(vec!["a".to_string(), "b,\"c\"".to_string(), "".to_string(), "d".to_string()]));
+        assert_eq!(split_csv("\"a\"b"), Some(vec!["ab".to_string()]));
+        assert_eq!(split_csv("\"a"), None);
+        assert_eq!(quote_csv("up{a=\"b\",c=\"d\"}"), "\"up{a=\"\"b\"\",c=\"\"d\"\"}\"");
+    }
+
+    #[test]
+    fn test_import_csv()
+    {
+        let csv = "host,when,series,v\n\
+                   web01,100,\"cpu{core=\"\"0\"\"}\",1.5\n\
+                   web01,110,\"cpu{core=\"\"0\"\",host=\"\"old\"\"}\",2.5\n\
+                   web02,100,mem,-inf\n";
+        let mapping = Mapping { labels: vec!["host".to_string()], timestamp: "when".to_string(), value: "v".to_string(), ..Mapping::default() };
+
+        let blocks = import_all(csv.as_bytes(), Format::Csv, &mapping).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(measurements(&blocks[&key("cpu", &[("core", "0"), ("host", "web01")])]),
+                   vec![Measurement{timestamp: 100, count: 1, value: 1.5}, Measurement{timestamp: 110, count: 1, value: 2.5}]);
+        assert_eq!(measurements(&blocks[&key("mem", &[("host", "web02")])]),
+                   vec![Measurement{timestamp: 100, count: 1, value: f64::NEG_INFINITY}]);
+
+        assert!(matches!(import_all(b"series,value\n", Format::Csv, &Mapping::default()),
+                         Err(ImportError::MissingColumn(ref c)) if c == "timestamp"));
+        assert!(matches!(import_all(b"series,timestamp,value\nup,1,1\nup,x,1\n", Format::Csv, &Mapping::default()),
+                         Err(ImportError::InvalidRow { line: 3, .. })));
+    }
+
+    #[test]
+    fn test_import_streams_blocks()
+    {
+        let csv = "series,timestamp,value\na,1,1\nb,1,1\na,2,2\na,3,3\nb,2,2\na,4,4\na,5,5\nc,1,1\n";
+        let mut blocks = Vec::new();
+
+        import(Cursor::new(csv), Format::Csv, &Mapping::default(), 2, |key, block| {
+            blocks.push((key.name().to_string(), measurements(&block).iter().map(|m| m.timestamp).collect::<Vec<_>>()));
+            Ok(())
+        }).unwrap();
+
+        // Full blocks as they fill, then the rest by series
+        assert_eq!(blocks, vec![("a".to_string(), vec![1, 2]), ("b".to_string(), vec![1, 2]), ("a".to_string(), vec![3, 4]),
+                                ("a".to_string(), vec![5]), ("c".to_string(), vec![1])]);
+
+        let result = import(Cursor::new(csv), Format::Csv, &Mapping::default(), 2, |_, _| Err(io::Error::other("disk full")));
+        assert!(matches!(result, Err(ImportError::Io(_))));
+    }
+
+    #[test]
+    fn test_roundtrip_ndjson()
+    {
+        let ndjson = "{\"series\": \"up{job=\\\"node\\\"}\", \"timestamp\": 100, \"count\": 3, \"value\": 1}\n\
+                      \n\
+                      {\"series\": \"up{job=\\\"node\\\"}\", \"timestamp\": \"200\", \"value\": \"NaN\"}\n\
+                      {\"series\": \"up{job=\\\"node\\\"}\", \"timestamp\": 300, \"value\": 0.25}\n";
+
+        let blocks = import_all(ndjson.as_bytes(), Format::Ndjson, &Mapping::default()).unwrap();
+
+        let mut out = Vec::new();
+        export(&mut out, Format::Ndjson, &blocks, 0..u64::MAX).unwrap();
+        let reimported = import_all(&out, Format::Ndjson, &Mapping::default()).unwrap();
+        assert_eq!(reimported.keys().collect::<Vec<_>>(), blocks.keys().collect::<Vec<_>>());
+
+        let original = measurements(&blocks[&key("up", &[("job", "node")])]);
+        let roundtrip = measurements(&reimported[&key("up", &[("job", "node")])]);
+        assert_eq!(roundtrip[0], Measurement{timestamp: 100, count: 3, value: 1.0});
+        assert!(roundtrip[1].value.is_nan());
+        assert_eq!((roundtrip[1].timestamp, roundtrip[1].count), (200, 1));
+        assert_eq!(roundtrip[2], original[2]);
+
+        let mut out = Vec::new();
+        export(&mut out, Format::Csv, &blocks, 150..300).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "series,timestamp,count,value\n\"up{job=\"\"node\"\"}\",200,1,NaN\n");
+
+        // Nothing past the end of the range is decoded, so a damaged tail doesn't matter
+        let series: Vec<Measurement> = (1..=4).map(|i| Measurement{timestamp: i * 100, count: 1, value: i as f64 * 0.1}).collect();
+        let block = Block::encode(&series).unwrap();
+        let truncated = Block { count: block.count, data: block.data[..block.data.len() - 1].to_vec() };
+
+        let mut out = Vec::new();
+        export(&mut out, Format::Csv, [(&key("up", &[]), &truncated)], 0..300).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 3);
+        assert!(export(&mut io::sink(), Format::Csv, [(&key("up", &[]), &truncated)], 0..401).is_err());
+    }
+}
@@ -0,0 +1,547 @@
+// Command line tools for working with block files, see USAGE.
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::slice;
+
+use serde_json::{json, Value};
+
+use crate::bulk;
+use crate::bulk::{parse_value, json_value, quote_csv, split_csv, ExportError, ImportError, Mapping};
+use crate::gorilla_tsz::Measurement;
+use crate::gorilla_tsz::block::{Block, BlockError, MAGIC};
+use crate::gorilla_tsz::codec::CodecMetadata;
+use crate::gorilla_tsz::codec::decoder;
+use crate::gorilla_tsz::codec::decoder::{DecoderError, Field};
+use crate::gorilla_tsz::codec::encoder::EncoderError;
+use crate::gorilla_tsz::codec::explain;
+use crate::ingest::SeriesKey;
+
+pub const USAGE: &str = "\
+usage: gorilla-tsdb <command> [options]
+
+commands:
+    encode [--format csv|json] <input> <block>   encode measurements into a block file
+    decode [--format csv|json] <block>           print the measurements of a block file
+    inspect <block>                              show a block file's layout
+    explain <block>                              show what every bit of a block file means
+    import [options] <input> <dir>               encode series data into block files in <dir>
+    export [options] <dir>                       print the series data in <dir>
+
+CSV rows are `timestamp,count,value` or `timestamp,value`, with an optional header. JSON
+is an array of {\"timestamp\", \"count\", \"value\"} objects, count defaulting to 1. The
+format defaults to JSON for .json files and CSV otherwise. `-` reads from stdin.
+
+import and export take --format csv|ndjson, defaulting to NDJSON for .ndjson files. Rows
+hold series, timestamp, count and value columns (or fields); --series, --timestamp,
+--count and --value read them from other columns and --label <column> adds a column to
+the series key. import writes blocks of up to --block-size measurements as 0.gtsz,
+1.gtsz, ... with index.csv naming each block's series. export takes --from and --to to
+limit the time range.";
+
+#[derive(Debug)]
+pub enum CliError {
+    Usage(String),
+    Io(io::Error),
+    Input(String),
+    Block(BlockError),
+    Encoder(EncoderError),
+    Decoder(DecoderError),
+    Import(ImportError),
+    Export(ExportError)
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(msg) => write!(f, "{}\n\n{}", msg, USAGE),
+            CliError::Io(e) => write!(f, "{}", e),
+            CliError::Input(msg) => write!(f, "invalid input: {}", msg),
+            CliError::Block(e) => write!(f, "invalid block file: {}", e),
+            CliError::Encoder(e) => write!(f, "encoding failed: {}", e),
+            CliError::Decoder(e) => write!(f, "decoding failed: {}", e),
+            CliError::Import(e) => write!(f, "import failed: {}", e),
+            CliError::Export(e) => write!(f, "export failed: {}", e)
+        }
+    }
+}
+
+impl error::Error for CliError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CliError::Usage(_) | CliError::Input(_) => None,
+            CliError::Io(e) => Some(e),
+            CliError::Block(e) => Some(e),
+            CliError::Encoder(e) => Some(e),
+            CliError::Decoder(e) => Some(e),
+            CliError::Import(e) => Some(e),
+            CliError::Export(e) => Some(e)
+        }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        CliError::Io(e)
+    }
+}
+
+impl From<BlockError> for CliError {
+    fn from(e: BlockError) -> Self {
+        CliError::Block(e)
+    }
+}
+
+impl From<EncoderError> for CliError {
+    fn from(e: EncoderError) -> Self {
+        CliError::Encoder(e)
+    }
+}
+
+impl From<DecoderError> for CliError {
+    fn from(e: DecoderError) -> Self {
+        CliError::Decoder(e)
+    }
+}
+
+impl From<ImportError> for CliError {
+    fn from(e: ImportError) -> Self {
+        CliError::Import(e)
+    }
+}
+
+impl From<ExportError> for CliError {
+    fn from(e: ExportError) -> Self {
+        CliError::Export(e)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    Csv,
+    Json
+}
+
+impl Format {
+    fn for_path(path: &str) -> Format {
+        match Path::new(path).extension() {
+            Some(ext) if ext == "json" => Format::Json,
+            _ => Format::Csv
+        }
+    }
+}
+
+fn read_input(path: &str) -> Result<Vec<u8>, CliError>
+{
+    let mut bytes = Vec::new();
+
+    if path == "-" {
+        io::stdin().read_to_end(&mut bytes)?;
+    } else {
+        bytes = fs::read(path)?;
+    }
+    Ok(bytes)
+}
+
+pub fn parse_csv(input: &str) -> Result<Vec<Measurement>, CliError>
+{
+    let mut measurements = Vec::new();
+
+    for (idx, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |what| CliError::Input(format!("line {}: invalid {}: {}", idx + 1, what, line));
+
+        let fields = split_csv(line).ok_or_else(|| invalid("row"))?;
+        let fields: Vec<&str> = fields.iter().map(|f| f.trim()).collect();
+
+        // Only the first line may be a header
+        if idx == 0 && fields[0].parse::<u64>().is_err() {
+            continue;
+        }
+
+        let (timestamp, count, value) = match fields[..] {
+            [timestamp, value] => (timestamp, "1", value),
+            [timestamp, count, value] => (timestamp, count, value),
+            _ => return Err(invalid("row"))
+        };
+
+        measurements.push(Measurement {
+            timestamp: timestamp.parse().map_err(|_| invalid("timestamp"))?,
+            count: count.parse().map_err(|_| invalid("count"))?,
+            value: parse_value(value).ok_or_else(|| invalid("value"))?
+        });
+    }
+
+    Ok(measurements)
+}
+
+pub fn parse_json(input: &str) -> Result<Vec<Measurement>, CliError>
+{
+    let json: Value = serde_json::from_str(input).map_err(|e| CliError::Input(e.to_string()))?;
+    let objects = json.as_array().ok_or_else(|| CliError::Input("expected an array of measurements".to_string()))?;
+
+    objects.iter().enumerate().map(|(idx, object)| {
+        let invalid = |what| CliError::Input(format!("measurement {}: invalid {}: {}", idx, what, object));
+
+        // Non-finite values can't be JSON numbers, so they're accepted as strings
+        let value = match object.get("value") {
+            Some(Value::String(v)) => parse_value(v),
+            Some(v) => v.as_f64(),
+            None => None
+        };
+
+        Ok(Measurement {
+            timestamp: object.get("timestamp").and_then(Value::as_u64).ok_or_else(|| invalid("timestamp"))?,
+            count: match object.get("count") {
+                None => 1,
+                Some(count) => count.as_u64().ok_or_else(|| invalid("count"))?
+            },
+            value: value.ok_or_else(|| invalid("value"))?
+        })
+    }).collect()
+}
+
+fn write_measurements(out: &mut dyn Write, measurements: &[Measurement], format: Format) -> Result<(), CliError>
+{
+    match format {
+        Format::Csv => {
+            writeln!(out, "timestamp,count,value")?;
+            for m in measurements {
+                writeln!(out, "{},{},{}", m.timestamp, m.count, m.value)?;
+            }
+        },
+        Format::Json => {
+            let objects: Vec<Value> = measurements.iter()
+                .map(|m| json!({"timestamp": m.timestamp, "count": m.count, "value": json_value(m.value)}))
+                .collect();
+            writeln!(out, "{}", Value::Array(objects))?;
+        }
+    }
+    Ok(())
+}
+
+fn encode(input: &str, output: &str, format: Option<Format>) -> Result<(), CliError>
+{
+    let bytes = read_input(input)?;
+    let text = String::from_utf8(bytes).map_err(|_| CliError::Input("not UTF-8".to_string()))?;
+
+    let measurements = match format.unwrap_or_else(|| Format::for_path(input)) {
+        Format::Csv => parse_csv(&text)?,
+        Format::Json => parse_json(&text)?
+    };
+
+    fs::write(output, Block::encode(&measurements)?.to_bytes())?;
+    Ok(())
+}
+
+fn decode(path: &str, format: Format, out: &mut dyn Write) -> Result<(), CliError>
+{
+    let block = Block::from_bytes(&read_input(path)?)?;
+    let measurements = block.measurements().collect::<Result<Vec<_>, _>>()?;
+
+    write_measurements(out, &measurements, format)
+}
+
+fn inspect(path: &str, out: &mut dyn Write) -> Result<(), CliError>
+{
+    let bytes = read_input(path)?;
+    let block = Block::from_bytes(&bytes)?;
+
+    let mut metadata = CodecMetadata::new();
+    let mut bits = [0usize; 3];
+    let mut first = None;
+    let mut last = None;
+
+    for _ in 0..block.count {
+        let measurement = decoder::decode_fields(&block.data, &mut metadata, |trace| {
+            bits[trace.field as usize] += trace.nbits;
+        })?;
+
+        first = first.or(Some(measurement));
+        last = Some(measurement);
+    }
+
+    let data_bits = block.data.len() * 8;
+    let per_measurement = |n: usize| if block.count == 0 { 0.0 } else { n as f64 / block.count as f64 };
+    let share = |n: usize| if data_bits == 0 { 0.0 } else { n as f64 * 100.0 / data_bits as f64 };
+
+    writeln!(out, "magic:                {}", String::from_utf8_lossy(MAGIC))?;
+    writeln!(out, "version:              {}", bytes[MAGIC.len()])?;
+    writeln!(out, "header bytes:         {}", block.header_len())?;
+    writeln!(out, "measurements:         {}", block.count)?;
+    writeln!(out, "data bytes:           {}", block.data.len())?;
+    writeln!(out, "bytes/measurement:    {:.2}", per_measurement(block.data.len()))?;
+
+    if let (Some(first), Some(last)) = (first, last) {
+        writeln!(out, "first timestamp:      {}", first.timestamp)?;
+        writeln!(out, "last timestamp:       {}", last.timestamp)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "{:<12} {:>10} {:>16} {:>8}", "field", "bits", "bits/measurement", "share")?;
+
+    let padding = data_bits - bits.iter().sum::<usize>();
+    let rows = [("timestamp", bits[Field::Timestamp as usize]), ("count", bits[Field::Count as usize]),
+                ("value", bits[Field::Value as usize]), ("padding", padding)];
+
+    for (name, n) in rows.iter() {
+        writeln!(out, "{:<12} {:>10} {:>16.2} {:>7.1}%", name, n, per_measurement(*n), share(*n))?;
+    }
+    Ok(())
+}
+
+fn explain(path: &str, out: &mut dyn Write) -> Result<(), CliError>
+{
+    let block = Block::from_bytes(&read_input(path)?)?;
+    let explanation = explain::explain(&block.data, block.count);
+
+    explain::write_table(&block.data, &explanation, out)?;
+
+    match explanation.error {
+        Some(e) => Err(CliError::Decoder(e)),
+        None => Ok(())
+    }
+}
+
+const INDEX_FILE: &str = "index.csv";
+
+fn import(input: &str, dir: &str, format: bulk::Format, mapping: &Mapping, block_size: usize) -> Result<(), CliError>
+{
+    let reader: Box<dyn Read> = if input == "-" { Box::new(io::stdin()) } else { Box::new(fs::File::open(input)?) };
+
+    fs::create_dir_all(dir)?;
+    let mut index = fs::File::create(Path::new(dir).join(INDEX_FILE))?;
+    writeln!(index, "block,series")?;
+
+    let mut blocks = 0;
+    bulk::import(BufReader::new(reader), format, mapping, block_size, |key, block| {
+        let name = format!("{}.gtsz", blocks);
+        blocks += 1;
+
+        fs::write(Path::new(dir).join(&name), block.to_bytes())?;
+        writeln!(index, "{},{}", name, quote_csv(&key.to_string()))
+    })?;
+
+    Ok(())
+}
+
+fn export(dir: &str, format: bulk::Format, range: Range<u64>, out: &mut dyn Write) -> Result<(), CliError>
+{
+    let index = fs::read_to_string(Path::new(dir).join(INDEX_FILE))?;
+    let mut series = Vec::new();
+
+    for (idx, line) in index.lines().enumerate().skip(1) {
+        let invalid = || CliError::Input(format!("{} line {}: {}", INDEX_FILE, idx + 1, line));
+
+        let (name, key) = match split_csv(line).as_deref() {
+            Some([name, key]) => (name.clone(), key.parse::<SeriesKey>().map_err(|_| invalid())?),
+            _ => return Err(invalid())
+        };
+
+        series.push((key, Block::from_bytes(&fs::read(Path::new(dir).join(name))?)?));
+    }
+
+    bulk::export(out, format, series.iter().map(|(key, block)| (key, block)), range)?;
+    Ok(())
+}
+
+fn parse_format(value: &str) -> Result<Format, CliError>
+{
+    match value {
+        "csv" => Ok(Format::Csv),
+        "json" => Ok(Format::Json),
+        other => Err(CliError::Usage(format!("unknown format: {}", other)))
+    }
+}
+
+fn parse_bulk_format(value: Option<&str>, path: &str) -> Result<bulk::Format, CliError>
+{
+    match value {
+        Some("csv") => Ok(bulk::Format::Csv),
+        Some("ndjson") => Ok(bulk::Format::Ndjson),
+        Some(other) => Err(CliError::Usage(format!("unknown format: {}", other))),
+        None => match Path::new(path).extension() {
+            Some(ext) if ext == "ndjson" => Ok(bulk::Format::Ndjson),
+            _ => Ok(bulk::Format::Csv)
+        }
+    }
+}
+
+fn option_value<'a>(rest: &mut slice::Iter<'a, String>, option: &str) -> Result<&'a str, CliError>
+{
+    rest.next().map(String::as_str).ok_or_else(|| CliError::Usage(format!("{} needs a value", option)))
+}
+
+fn option_number<T: std::str::FromStr>(rest: &mut slice::Iter<String>, option: &str) -> Result<T, CliError>
+{
+    let value = option_value(rest, option)?;
+    value.parse().map_err(|_| CliError::Usage(format!("invalid {}: {}", option, value)))
+}
+
+// Runs the command in `args`, which excludes the program name
+pub fn run(args: &[String], out: &mut dyn Write) -> Result<(), CliError>
+{
+    let command = args.first().ok_or_else(|| CliError::Usage("missing command".to_string()))?;
+
+    let mut format = None;
+    let mut mapping = Mapping::default();
+    let mut range = 0..u64::MAX;
+    let mut block_size = bulk::DEFAULT_BLOCK_SIZE;
+    let mut paths = Vec::new();
+    let mut rest = args[1..].iter();
+
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--format" => format = Some(option_value(&mut rest, arg)?),
+            "--series" => mapping.series = option_value(&mut rest, arg)?.to_string(),
+            "--timestamp" => mapping.timestamp = option_value(&mut rest, arg)?.to_string(),
+            "--count" => mapping.count = option_value(&mut rest, arg)?.to_string(),
+            "--value" => mapping.value = option_value(&mut rest, arg)?.to_string(),
+            "--label" => mapping.labels.push(option_value(&mut rest, arg)?.to_string()),
+            "--block-size" => block_size = match option_number(&mut rest, arg)? {
+                0 => return Err(CliError::Usage(format!("invalid {}: 0", arg))),
+                size => size
+            },
+            "--from" => range.start = option_number(&mut rest, arg)?,
+            "--to" => range.end = option_number(&mut rest, arg)?,
+            _ => paths.push(arg.as_str())
+        }
+    }
+
+    match (command.as_str(), &paths[..]) {
+        ("encode", [input, output]) => encode(input, output, format.map(parse_format).transpose()?),
+        ("decode", [path]) => decode(path, format.map_or(Ok(Format::Csv), parse_format)?, out),
+        ("inspect", [path]) => inspect(path, out),
+        ("explain", [path]) => explain(path, out),
+        ("import", [input, dir]) => import(input, dir, parse_bulk_format(format, input)?, &mapping, block_size),
+        ("export", [dir]) => export(dir, parse_bulk_format(format, "")?, range, out),
+        ("help", []) | ("--help", []) => Ok(writeln!(out, "{}", USAGE)?),
+        ("encode", _) | ("decode", _) | ("inspect", _) | ("explain", _) | ("import", _) | ("export", _) =>
+            Err(CliError::Usage(format!("wrong arguments for {}", command))),
+        _ => Err(CliError::Usage(format!("unknown command: {}", command)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_input()
+    {
+        let csv = "timestamp,count,value\n1567029708,2,1.5\n\n1567029718,3,-inf\n1567029728,NaN\n";
+        let measures = parse_csv(csv).unwrap();
+        assert_eq!(measures[..2], [Measurement{timestamp: 1567029708, count: 2, value: 1.5},
+                                   Measurement{timestamp: 1567029718, count: 3, value: f64::NEG_INFINITY}]);
+        assert_eq!((measures[2].count, measures[2].value.is_nan()), (1, true));
+
+        assert_eq!(parse_csv("\"1567029708\", \"2\" ,\"1.5\"").unwrap(), vec![Measurement{timestamp: 1567029708, count: 2, value: 1.5}]);
+        assert!(matches!(parse_csv("1,2,3,4"), Err(CliError::Input(_))));
+        assert!(matches!(parse_csv("1,\"2,3"), Err(CliError::Input(_))));
+        assert!(matches!(parse_csv("1,1\nts,1"), Err(CliError::Input(_))));
+
+        let json = r#"[{"timestamp": 10, "value": 2.5}, {"timestamp": 20, "count": 4, "value": "Infinity"}]"#;
+        assert_eq!(parse_json(json).unwrap(), vec![Measurement{timestamp: 10, count: 1, value: 2.5},
+                                                   Measurement{timestamp: 20, count: 4, value: f64::INFINITY}]);
+        assert!(matches!(parse_json(r#"[{"timestamp": -1, "value": 1}]"#), Err(CliError::Input(_))));
+        assert!(matches!(parse_json(r#"{"timestamp": 1}"#), Err(CliError::Input(_))));
+    }
+
+    #[test]
+    fn test_commands()
+    {
+        let dir = std::env::temp_dir().join(format!("gorilla-tsdb-cli-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let input = dir.join("input.csv");
+        let block = dir.join("block.gtsz");
+        let (input, block) = (input.to_str().unwrap(), block.to_str().unwrap());
+
+        fs::write(input, "1567029708,1,1.5\n1567029768,1,1.5\n1567029828,2,-0.25\n").unwrap();
+        run(&args(&["encode", input, block]), &mut io::sink()).unwrap();
+
+        let mut out = Vec::new();
+        run(&args(&["decode", block]), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "timestamp,count,value\n1567029708,1,1.5\n1567029768,1,1.5\n1567029828,2,-0.25\n");
+
+        let mut out = Vec::new();
+        run(&args(&["decode", "--format", "json", block]), &mut out).unwrap();
+        assert_eq!(parse_json(&String::from_utf8(out).unwrap()).unwrap().len(), 3);
+
+        let mut out = Vec::new();
+        run(&args(&["inspect", block]), &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("measurements:         3\n"), "{}", report);
+        assert!(report.contains("last timestamp:       1567029828\n"), "{}", report);
+        assert!(report.lines().any(|l| l.starts_with("timestamp ")), "{}", report);
+
+        let mut out = Vec::new();
+        run(&args(&["explain", block]), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 1 + 3 * 3);
+
+        assert!(matches!(run(&args(&["decode", input]), &mut io::sink()), Err(CliError::Block(BlockError::InvalidMagic))));
+        assert!(matches!(run(&args(&["frobnicate"]), &mut io::sink()), Err(CliError::Usage(_))));
+        assert!(matches!(run(&args(&["encode", input]), &mut io::sink()), Err(CliError::Usage(_))));
+        assert!(matches!(run(&args(&["decode", "--format", "xml", block]), &mut io::sink()), Err(CliError::Usage(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_export()
+    {
+        let dir = std::env::temp_dir().join(format!("gorilla-tsdb-cli-bulk-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let input = dir.join("input.csv");
+        let blocks = dir.join("blocks");
+        let (input, blocks) = (input.to_str().unwrap(), blocks.to_str().unwrap());
+
+        fs::write(input, "host,ts,series,v\n\
+                          web01,100,cpu,1.5\n\
+                          web01,110,cpu,2.5\n\
+                          web02,100,\"mem{kind=\"\"rss\"\"}\",-inf\n\
+                          web01,120,cpu,3.5\n").unwrap();
+        run(&args(&["import", "--label", "host", "--timestamp", "ts", "--value", "v", "--block-size", "2", input, blocks]),
+            &mut io::sink()).unwrap();
+
+        let index = fs::read_to_string(Path::new(blocks).join(INDEX_FILE)).unwrap();
+        assert_eq!(index.lines().count(), 1 + 3);
+
+        let mut out = Vec::new();
+        run(&args(&["export", "--from", "105", blocks]), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "series,timestamp,count,value\n\
+                    \"cpu{host=\"\"web01\"\"}\",110,1,2.5\n\
+                    \"cpu{host=\"\"web01\"\"}\",120,1,3.5\n");
+
+        let mut out = Vec::new();
+        run(&args(&["export", "--format", "ndjson", "--to", "101", blocks]), &mut out).unwrap();
+        let rows: Vec<Value> = String::from_utf8(out).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1]["series"], "mem{host=\"web02\",kind=\"rss\"}");
+        assert_eq!(rows[1]["value"], "-Infinity");
+
+        assert!(matches!(run(&args(&["import", "--value", "value", input, blocks]), &mut io::sink()),
+                         Err(CliError::Import(ImportError::MissingColumn(_)))));
+        assert!(matches!(run(&args(&["export", "--from", "soon", blocks]), &mut io::sink()), Err(CliError::Usage(_))));
+        assert!(matches!(run(&args(&["import", "--block-size", "0", input, blocks]), &mut io::sink()), Err(CliError::Usage(_))));
+        assert!(matches!(run(&args(&["export", "--format", "json", blocks]), &mut io::sink()), Err(CliError::Usage(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+// A self describing container for an encoded block, for storing one in a file:
+//
+//   "GTSZ"  magic
+//   u8      format version
+//   varint  measurement count
+//   ...     encoded measurements
+//
+// Blocks don't record how many measurements they hold, so the container carries it.
+
+use std::error;
+use std::fmt;
+
+use super::Measurement;
+use super::codec::CodecMetadata;
+use super::codec::encoder;
+use super::codec::encoder::{EncoderError, MAX_MEASUREMENT_BYTES};
+use super::codec::decoder;
+use super::utils::varint;
+
+pub const MAGIC: &[u8; 4] = b"GTSZ";
+pub const VERSION: u8 = 1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BlockError {
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    InvalidCount
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::InvalidMagic => write!(f, "not a block: missing GTSZ magic"),
+            BlockError::UnsupportedVersion(version) => write!(f, "unsupported block version {}", version),
+            BlockError::Truncated => write!(f, "block header truncated"),
+            BlockError::InvalidCount => write!(f, "measurement count exceeds the block's data")
+        }
+    }
+}
+
+impl error::Error for BlockError {}
+
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    pub count: usize,
+    pub data: Vec<u8>
+}
+
+// Encodes measurements into a block one at a time, only holding on to the encoded bytes
+pub struct BlockEncoder {
+    data: Vec<u8>,
+    metadata: CodecMetadata
+}
+
+impl BlockEncoder {
+    pub fn new() -> BlockEncoder {
+        BlockEncoder { data: Vec::new(), metadata: CodecMetadata::new() }
+    }
+
+    pub fn push(&mut self, measurement: &Measurement) -> Result<(), EncoderError> {
+        self.data.resize(self.metadata.byte_len() + MAX_MEASUREMENT_BYTES, 0);
+        encoder::encode(&mut self.data, &mut self.metadata, measurement)
+    }
+
+    pub fn measurement_count(&self) -> usize {
+        self.metadata.measurement_count()
+    }
+
+    pub fn finish(mut self) -> Block {
+        self.data.truncate(self.metadata.byte_len());
+        Block { count: self.metadata.measurement_count(), data: self.data }
+    }
+}
+
+impl Default for BlockEncoder {
+    fn default() -> Self {
+        BlockEncoder::new()
+    }
+}
+
+impl Block {
+    pub fn encode(measurements: &[Measurement]) -> Result<Block, EncoderError> {
+        let mut encoder = BlockEncoder::new();
+
+        for measurement in measurements {
+            encoder.push(measurement)?;
+        }
+
+        Ok(encoder.finish())
+    }
+
+    pub fn measurements(&self) -> decoder::Measurements<'_> {
+        decoder::measurements(&self.data, self.count)
+    }
+
+    pub fn header_len(&self) -> usize {
+        let mut varint_buf = [0u8; 10];
+        MAGIC.len() + 1 + varint::encode(self.count as u64, &mut varint_buf).unwrap_or(0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut varint_buf = [0u8; 10];
+        let sz = varint::encode(self.count as u64, &mut varint_buf).unwrap_or(0);
+
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + sz + self.data.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&varint_buf[..sz]);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Block, BlockError> {
+        if bytes.len() < MAGIC.len() + 1 {
+            return Err(BlockError::Truncated);
+        }
+
+        if &bytes[..MAGIC.len()] != MAGIC {
+            return Err(BlockError::InvalidMagic);
+        }
+
+        let version = bytes[MAGIC.len()];
+        if version != VERSION {
+            return Err(BlockError::UnsupportedVersion(version));
+        }
+
+        let rest = &bytes[MAGIC.len() + 1..];
+        let (count, sz) = varint::decode(rest).map_err(|_| BlockError::Truncated)?;
+
+        // Every measurement takes at least a bit
+        if count > (rest.len() - sz) as u64 * 8 {
+            return Err(BlockError::InvalidCount);
+        }
+
+        Ok(Block { count: count as usize, data: rest[sz..].to_vec() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_roundtrip()
+    {
+        let measures: Vec<Measurement> = (0..50u64)
+            .map(|i| Measurement{timestamp: 1567029708 + i * 10, count: 1 + i % 2, value: (i as f64).sqrt()})
+            .collect();
+
+        let block = Block::encode(&measures).unwrap();
+        assert_eq!(block.count, 50);
+
+        let bytes = block.to_bytes();
+        assert_eq!(&bytes[..6], b"GTSZ\x01\x32");
+        assert_eq!(block.header_len(), 6);
+
+        let decoded = Block::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, block);
+        assert_eq!(decoded.measurements().collect::<Result<Vec<_>, _>>().unwrap(), measures);
+
+        assert_eq!(Block::encode(&[]).unwrap(), Block { count: 0, data: vec![] });
+    }
+
+    #[test]
+    fn test_invalid_blocks()
+    {
+        assert_eq!(Block::from_bytes(b"GTS"), Err(BlockError::Truncated));
+        assert_eq!(Block::from_bytes(b"TSDB\x01\x00"), Err(BlockError::InvalidMagic));
+        assert_eq!(Block::from_bytes(b"GTSZ\x02\x00"), Err(BlockError::UnsupportedVersion(2)));
+        assert_eq!(Block::from_bytes(b"GTSZ\x01"), Err(BlockError::Truncated));
+        assert_eq!(Block::from_bytes(b"GTSZ\x01\x09\x00"), Err(BlockError::InvalidCount));
+    }
+}
@@ -0,0 +1,272 @@
+// Beringei time series streams (beringei/lib/TimeSeriesStream.cpp), the open source
+// implementation of the Gorilla paper. Timestamps are 32 bit Unix seconds: the first is
+// stored in 31 bits and the rest as delta-of-deltas in 7/9/12/32 bit buckets, starting from
+// an assumed 60s delta. Values use the paper's XOR scheme against an initial value of 0.
+//
+// Beringei keeps the number of points outside the stream, so decoding needs it passed in.
+// There is no per-point count either: decoded measurements get a count of 1 and encoding
+// ignores it.
+
+use std::error;
+use std::fmt;
+
+use super::Measurement;
+use super::super::utils::bitcopy::{BitCopyError, BitValue};
+use super::super::utils::bitstream::{BitReader, BitWriter};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamError {
+    BitCopy(BitCopyError),
+    // Timestamps must fit in 32 bits, or 31 for the first
+    TimestampOutOfRange { index: usize, timestamp: i64 },
+    DeltaOfDeltaOutOfRange { index: usize, dod: i64 },
+    InvalidBlock { index: usize, leading: u32, bits: u32 }
+}
+
+impl StreamError {
+    // The stream ended before the expected number of points
+    pub fn is_truncated(&self) -> bool {
+        matches!(self, StreamError::BitCopy(BitCopyError::SourceOverrun { .. }))
+    }
+}
+
+impl From<BitCopyError> for StreamError {
+    fn from(e: BitCopyError) -> Self {
+        StreamError::BitCopy(e)
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::BitCopy(e) => write!(f, "{}", e),
+            StreamError::TimestampOutOfRange { index, timestamp } => write!(f, "timestamp out of range in point {}: {}", index, timestamp),
+            StreamError::DeltaOfDeltaOutOfRange { index, dod } => write!(f, "timestamp delta-of-delta out of range in point {}: {}", index, dod),
+            StreamError::InvalidBlock { index, leading, bits } =>
+                write!(f, "invalid block in point {}: {} leading zeros, {} bits", index, leading, bits)
+        }
+    }
+}
+
+impl error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            StreamError::BitCopy(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+const FIRST_TIMESTAMP_BITS: usize = 31;
+const DEFAULT_DELTA: i64 = 60;
+
+const LEADING_ZEROS_BITS: usize = 5;
+const BLOCK_SIZE_BITS: usize = 6;
+const MAX_LEADING_ZEROS: u32 = (1 << LEADING_ZEROS_BITS) - 1;
+
+// Delta-of-delta buckets, as (value bits, control bits, control length)
+const DOD_BUCKETS: [(usize, u64, usize); 4] = [(7, 0b10, 2), (9, 0b110, 3), (12, 0b1110, 4), (32, 0b1111, 4)];
+
+pub fn encode_stream(measurements: &[Measurement]) -> Result<Vec<u8>, StreamError>
+{
+    let mut writer = BitWriter::new();
+
+    let mut prev_timestamp = 0i64;
+    let mut prev_delta = DEFAULT_DELTA;
+    let mut prev_value = 0u64;
+    let mut prev_leading = 0u32;
+    let mut prev_trailing = 0u32;
+
+    for (idx, measurement) in measurements.iter().enumerate() {
+        let max_timestamp = if idx == 0 { 1 << FIRST_TIMESTAMP_BITS } else { 1 << 32 };
+
+        // Past i64::MAX is still out of range, so it's saturated for reporting
+        let timestamp = measurement.timestamp.min(i64::MAX as u64) as i64;
+
+        if measurement.timestamp >= max_timestamp {
+            return Err(StreamError::TimestampOutOfRange { index: idx, timestamp });
+        }
+
+        if idx == 0 {
+            writer.write(measurement.timestamp, FIRST_TIMESTAMP_BITS)?;
+        } else {
+            let delta = timestamp - prev_timestamp;
+            let mut dod = delta - prev_delta;
+
+            if dod == 0 {
+                writer.write_bit(BitValue::Zero)?;
+            } else {
+                // Zero has its own encoding, so positive values are shifted down by one
+                if dod > 0 {
+                    dod -= 1;
+                }
+
+                let &(nbits, control, control_bits) = DOD_BUCKETS.iter()
+                    .find(|&&(nbits, _, _)| dod.abs() < 1 << (nbits - 1))
+                    .ok_or(StreamError::DeltaOfDeltaOutOfRange { index: idx, dod })?;
+
+                writer.write(control, control_bits)?;
+                writer.write((dod + (1 << (nbits - 1))) as u64, nbits)?;
+            }
+
+            prev_delta = delta;
+        }
+
+        prev_timestamp = timestamp;
+
+        let value = measurement.value.to_bits();
+        let xor = prev_value ^ value;
+        prev_value = value;
+
+        if xor == 0 {
+            writer.write_bit(BitValue::Zero)?;
+            continue;
+        }
+
+        writer.write_bit(BitValue::One)?;
+
+        let leading = u32::min(xor.leading_zeros(), MAX_LEADING_ZEROS);
+        let trailing = xor.trailing_zeros();
+        let block_size = 64 - leading - trailing;
+        let prev_block_size = 64 - prev_leading - prev_trailing;
+
+        // Only reuse the previous block when that's actually smaller than a new header
+        if leading >= prev_leading && trailing >= prev_trailing &&
+            (prev_block_size as usize) < LEADING_ZEROS_BITS + BLOCK_SIZE_BITS + block_size as usize {
+            writer.write_bit(BitValue::One)?;
+            writer.write(xor >> prev_trailing, prev_block_size as usize)?;
+        } else {
+            writer.write_bit(BitValue::Zero)?;
+            writer.write(leading as u64, LEADING_ZEROS_BITS)?;
+            writer.write((block_size - 1) as u64, BLOCK_SIZE_BITS)?;
+            writer.write(xor >> trailing, block_size as usize)?;
+
+            prev_leading = leading;
+            prev_trailing = trailing;
+        }
+    }
+
+    Ok(writer.into_bytes())
+}
+
+pub fn decode_stream(data: &[u8], count: usize) -> Result<Vec<Measurement>, StreamError>
+{
+    let mut reader = BitReader::new(data);
+    let mut measurements = Vec::new();
+
+    let mut timestamp = 0i64;
+    let mut delta = DEFAULT_DELTA;
+    let mut value = 0u64;
+    let mut prev_leading = 0u32;
+    let mut prev_trailing = 0u32;
+
+    for idx in 0..count {
+        if idx == 0 {
+            timestamp = reader.read(FIRST_TIMESTAMP_BITS)? as i64;
+        } else {
+            let mut control = 0;
+            while control < DOD_BUCKETS.len() && reader.read_bit()? == BitValue::One {
+                control += 1;
+            }
+
+            if control > 0 {
+                let nbits = DOD_BUCKETS[control - 1].0;
+                let mut dod = reader.read(nbits)? as i64 - (1 << (nbits - 1));
+
+                if dod >= 0 {
+                    dod += 1;
+                }
+
+                delta += dod;
+            }
+
+            timestamp += delta;
+        }
+
+        if !(0..1 << 32).contains(&timestamp) {
+            return Err(StreamError::TimestampOutOfRange { index: idx, timestamp });
+        }
+
+        if reader.read_bit()? == BitValue::One {
+            let xor = if reader.read_bit()? == BitValue::One {
+                reader.read((64 - prev_leading - prev_trailing) as usize)? << prev_trailing
+            } else {
+                let leading = reader.read(LEADING_ZEROS_BITS)? as u32;
+                let block_size = reader.read(BLOCK_SIZE_BITS)? as u32 + 1;
+
+                prev_trailing = 64u32.checked_sub(leading + block_size)
+                    .ok_or(StreamError::InvalidBlock { index: idx, leading, bits: block_size })?;
+                prev_leading = leading;
+
+                reader.read(block_size as usize)? << prev_trailing
+            };
+
+            value ^= xor;
+        }
+
+        measurements.push(Measurement { timestamp: timestamp as u64, count: 1, value: f64::from_bits(value) });
+    }
+
+    Ok(measurements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Worked out by hand from TimeSeriesStream.cpp:
+    //   1567029708 in 31 bits; 1.0: 1 0 00010 001001 1111111111
+    //   +60s: dod 0; value unchanged: 0 0
+    //   +61s: dod 1, shifted to 0: 10 1000000; 2.0: 1 0 00001 001010 11111111111
+    const FIXTURE: [u8; 12] = [0xba, 0xcd, 0xf3, 0x99, 0x08, 0x9f, 0xfc, 0xa0, 0x41, 0x2b, 0xff, 0x80];
+
+    fn fixture_measurements() -> Vec<Measurement> {
+        vec![Measurement{timestamp: 1567029708, count: 1, value: 1.0},
+             Measurement{timestamp: 1567029768, count: 1, value: 1.0},
+             Measurement{timestamp: 1567029829, count: 1, value: 2.0}]
+    }
+
+    #[test]
+    fn test_fixture()
+    {
+        assert_eq!(decode_stream(&FIXTURE, 3).unwrap(), fixture_measurements());
+        assert_eq!(encode_stream(&fixture_measurements()).unwrap(), FIXTURE.to_vec());
+    }
+
+    #[test]
+    fn test_roundtrip()
+    {
+        let mut measures = Vec::new();
+        let mut timestamp = 1567029708u64;
+
+        for (i, jitter) in [0i64, 64, -63, 255, -255, 2047, -2047, 100000, -100000, 1, 0].iter().enumerate() {
+            timestamp = (timestamp as i64 + 60 + jitter) as u64;
+            measures.push(Measurement{timestamp, count: 1, value: 43.568 + (i as f64 * 0.0023456)});
+        }
+
+        measures.push(Measurement{timestamp: timestamp + 10, count: 1, value: -1.0000000000000002});
+        measures.push(Measurement{timestamp: timestamp + 20, count: 1, value: f64::from_bits(0x7ff0000000000002)});
+        measures.push(Measurement{timestamp: timestamp + 30, count: 1, value: 0.0});
+
+        let stream = encode_stream(&measures).unwrap();
+        let decoded = decode_stream(&stream, measures.len()).unwrap();
+
+        for (a, b) in measures.iter().zip(decoded.iter()) {
+            assert_eq!(a.timestamp, b.timestamp);
+            assert_eq!(a.value.to_bits(), b.value.to_bits());
+        }
+    }
+
+    #[test]
+    fn test_invalid_streams()
+    {
+        assert_eq!(encode_stream(&[Measurement{timestamp: 1 << 31, count: 1, value: 0.0}]),
+                   Err(StreamError::TimestampOutOfRange { index: 0, timestamp: 1 << 31 }));
+        assert!(matches!(encode_stream(&[Measurement{timestamp: 0, count: 1, value: 0.0},
+                                         Measurement{timestamp: (1 << 31) + (1 << 30), count: 1, value: 0.0}]),
+                         Err(StreamError::DeltaOfDeltaOutOfRange { index: 1, .. })));
+        assert!(decode_stream(&FIXTURE[..8], 3).unwrap_err().is_truncated());
+        assert!(decode_stream(&[0xff; 16], 4).is_err());
+        assert_eq!(decode_stream(&[], 0).unwrap(), vec![]);
+    }
+}
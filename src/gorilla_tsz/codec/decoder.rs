@@ -1,35 +1,95 @@
+use std::error;
+use std::fmt;
+
 use super::CodecMetadata;
 use super::Measurement;
-use super::{int_to_double, double_to_int};
 use super::super::utils::bitcopy;
-use super::super::utils::bitcopy::BitValue;
+use super::super::utils::bitcopy::{BitCopyError, BitValue};
 use super::super::utils::varint;
+use super::super::utils::varint::VarIntError;
+
+pub use super::Field;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecoderErrorKind {
+    BitCopy(BitCopyError),
+    VarInt(VarIntError),
+    // Ten bytes without the last byte marker
+    UnterminatedVarint,
+    // The decoder state has no previous measurement, or no xor window to reuse
+    MissingPrevious,
+    MissingXorWindow,
+    InvalidXorWindow { leading: u8, sig_bits: usize },
+    ZeroXorWindow
+}
+
+impl From<BitCopyError> for DecoderErrorKind {
+    fn from(e: BitCopyError) -> Self {
+        DecoderErrorKind::BitCopy(e)
+    }
+}
+
+impl From<VarIntError> for DecoderErrorKind {
+    fn from(e: VarIntError) -> Self {
+        DecoderErrorKind::VarInt(e)
+    }
+}
 
-#[derive(Debug)]
-pub enum DecoderError {
-    Generic(String),
-    BitCopyError(bitcopy::BitCopyError),
-    VarintError(varint::VarIntError)
+impl fmt::Display for DecoderErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecoderErrorKind::BitCopy(e) => write!(f, "{}", e),
+            DecoderErrorKind::VarInt(e) => write!(f, "{}", e),
+            DecoderErrorKind::UnterminatedVarint => write!(f, "could not find end of varint"),
+            DecoderErrorKind::MissingPrevious => write!(f, "no previous measurement"),
+            DecoderErrorKind::MissingXorWindow => write!(f, "no previous xor window"),
+            DecoderErrorKind::InvalidXorWindow { leading, sig_bits } =>
+                write!(f, "invalid xor window: {} leading zeros, {} significant bits", leading, sig_bits),
+            DecoderErrorKind::ZeroXorWindow => write!(f, "zero xor in new window")
+        }
+    }
 }
 
-impl From<bitcopy::BitCopyError> for DecoderError {
-    fn from(e: bitcopy::BitCopyError) -> Self {
-        DecoderError::BitCopyError(e)
+// A failure to decode a field, with the measurement it belongs to and the bit it starts at
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecoderError {
+    pub index: usize,
+    pub offset: usize,
+    pub field: Field,
+    pub kind: DecoderErrorKind
+}
+
+impl DecoderError {
+    // The input ended part way through, as opposed to holding bits no encoder writes
+    pub fn is_truncated(&self) -> bool {
+        matches!(self.kind, DecoderErrorKind::BitCopy(BitCopyError::SourceOverrun { .. }) |
+                            DecoderErrorKind::VarInt(VarIntError::Truncated(_)))
     }
 }
 
-impl From<varint::VarIntError> for DecoderError {
-    fn from(e: varint::VarIntError) -> Self {
-        DecoderError::VarintError(e)
+impl fmt::Display for DecoderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "measurement {}: {} at bit {}: {}", self.index, self.field, self.offset, self.kind)
     }
 }
 
+impl error::Error for DecoderError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.kind {
+            DecoderErrorKind::BitCopy(e) => Some(e),
+            DecoderErrorKind::VarInt(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+// Wraps around like the encoder's deltas, so no input can overflow
 fn delta_add(val: u64, delta: i64) -> u64
 {
-    (val as i64 + delta) as u64
+    val.wrapping_add(delta as u64)
 }
 
-fn read_varint(buf: &[u8], metadata: &mut CodecMetadata) -> Result<u64, DecoderError>
+fn read_varint(buf: &[u8], metadata: &mut CodecMetadata) -> Result<u64, DecoderErrorKind>
 {
     let mut bytes = [0u8; 10];
 
@@ -47,22 +107,20 @@ fn read_varint(buf: &[u8], metadata: &mut CodecMetadata) -> Result<u64, DecoderE
         }
     }
 
-    Err(DecoderError::Generic("Could not find end of varint".to_string()))
+    Err(DecoderErrorKind::UnterminatedVarint)
 }
 
-fn read_double(buf: &[u8], metadata: &mut CodecMetadata) -> Result<f64, DecoderError>
+fn read_double(buf: &[u8], metadata: &mut CodecMetadata) -> Result<f64, DecoderErrorKind>
 {
     let mut bytes = [0u8; 8];
 
     bitcopy::copy(&mut bytes, buf, 8 * 8, 0, metadata.buf_offbits)?;
     metadata.buf_offbits += 8 * 8;
 
-    let intval = u64::from_be_bytes(bytes);
-
-    Ok(unsafe { int_to_double(intval) })
+    Ok(f64::from_bits(u64::from_be_bytes(bytes)))
 }
 
-fn read_bit(buf: &[u8], metadata: &mut CodecMetadata) -> Result<BitValue, DecoderError>
+fn read_bit(buf: &[u8], metadata: &mut CodecMetadata) -> Result<BitValue, DecoderErrorKind>
 {
 
     let result = bitcopy::read_bit(buf, metadata.buf_offbits)?;
@@ -71,7 +129,7 @@ fn read_bit(buf: &[u8], metadata: &mut CodecMetadata) -> Result<BitValue, Decode
     Ok(result)
 }
 
-fn read_bits(src: &[u8], metadata: &mut CodecMetadata, dst: &mut [u8], dst_offbits: usize, nbits: usize) -> Result<(), DecoderError>
+fn read_bits(src: &[u8], metadata: &mut CodecMetadata, dst: &mut [u8], dst_offbits: usize, nbits: usize) -> Result<(), DecoderErrorKind>
 {
 
     bitcopy::copy(dst, src, nbits, dst_offbits, metadata.buf_offbits)?;
@@ -80,105 +138,333 @@ fn read_bits(src: &[u8], metadata: &mut CodecMetadata, dst: &mut [u8], dst_offbi
     Ok(())
 }
 
-pub fn decode(buf: &[u8], metadata: &mut CodecMetadata) -> Result<Measurement, DecoderError>
-{
-    let measurement;
+// Which encoding a field was read with, for explaining a block bit by bit
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Path {
+    // Fields of the first measurement are written in full
+    First,
+    TimestampDelta(i64),
+    DeltaOfDeltaZero,
+    DeltaOfDelta(i64),
+    CountRepeat,
+    CountDelta(i64),
+    ValueRepeat,
+    XorReuseWindow { leading: u32, trailing: u32 },
+    XorNewWindow { leading: u32, sig_bits: u32 }
+}
 
-    if metadata.idx == 0 {
-        let timestamp = read_varint(buf, metadata)?;
-        let count = read_varint(buf, metadata)?;
-        let value = read_double(buf, metadata)?;
+impl Path {
+    // Widths of the control bits that lead the field, before its payload
+    pub fn control_bits(&self) -> &'static [usize] {
+        match self {
+            Path::First | Path::TimestampDelta(_) => &[],
+            Path::DeltaOfDeltaZero | Path::DeltaOfDelta(_) => &[1],
+            Path::CountRepeat | Path::CountDelta(_) => &[1],
+            Path::ValueRepeat => &[1],
+            Path::XorReuseWindow { .. } => &[1, 1],
+            Path::XorNewWindow { .. } => &[1, 1, 6, 6]
+        }
+    }
+}
 
-        measurement = Measurement{timestamp, count, value};
-    } else {
-        let last_measurement = match metadata.last_measurement {
-            None => return Err(DecoderError::Generic("No previous measurement".to_string())),
-            Some(measure) => measure
-        };
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Path::First => write!(f, "first"),
+            Path::TimestampDelta(delta) => write!(f, "delta {}", delta),
+            Path::DeltaOfDeltaZero => write!(f, "delta-of-delta zero"),
+            Path::DeltaOfDelta(dod) => write!(f, "delta-of-delta {}", dod),
+            Path::CountRepeat => write!(f, "count repeat"),
+            Path::CountDelta(delta) => write!(f, "count delta {}", delta),
+            Path::ValueRepeat => write!(f, "xor zero"),
+            Path::XorReuseWindow { leading, trailing } => write!(f, "xor reuse window ({} leading, {} trailing)", leading, trailing),
+            Path::XorNewWindow { leading, sig_bits } => write!(f, "xor new window ({} leading, {} significant)", leading, sig_bits)
+        }
+    }
+}
+
+// Where a field was read from and how
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FieldTrace {
+    pub field: Field,
+    pub offset: usize,
+    pub nbits: usize,
+    pub path: Path,
+    // The decoded timestamp or count, or the bits of the value
+    pub value: u64
+}
+
+fn decode_timestamp(buf: &[u8], metadata: &mut CodecMetadata, last_measurement: &Measurement) -> Result<(u64, Path), DecoderErrorKind>
+{
+    if metadata.idx == 1 {
+        let timestamp_delta = varint::decode_zigzag(read_varint(buf, metadata)?);
+        metadata.last_timestamp_delta = timestamp_delta;
+
+        return Ok((delta_add(last_measurement.timestamp, timestamp_delta), Path::TimestampDelta(timestamp_delta)));
+    }
 
-        let timestamp;
+    match read_bit(buf, metadata)? {
+        BitValue::Zero => Ok((delta_add(last_measurement.timestamp, metadata.last_timestamp_delta), Path::DeltaOfDeltaZero)),
+        BitValue::One => {
+            let timestamp_delta2 = varint::decode_zigzag(read_varint(buf, metadata)?);
+            let timestamp_delta = metadata.last_timestamp_delta.wrapping_add(timestamp_delta2);
 
-        if metadata.idx == 1 {
-            let timestamp_delta = varint::decode_zigzag(read_varint(buf, metadata)?);
-            timestamp = delta_add(last_measurement.timestamp, timestamp_delta);
             metadata.last_timestamp_delta = timestamp_delta;
-        } else {
-            match read_bit(buf, metadata)? {
-                BitValue::Zero => {
-                    timestamp = delta_add(last_measurement.timestamp, metadata.last_timestamp_delta);
-                },
-                BitValue::One => {
-                    let timestamp_delta2 = varint::decode_zigzag(read_varint(buf, metadata)?);
-                    let timestamp_delta = metadata.last_timestamp_delta + timestamp_delta2;
-
-                    timestamp = delta_add(last_measurement.timestamp, timestamp_delta);
-                    metadata.last_timestamp_delta = timestamp_delta;
-                }
-            }
+            Ok((delta_add(last_measurement.timestamp, timestamp_delta), Path::DeltaOfDelta(timestamp_delta2)))
         }
+    }
+}
 
-        let count;
-        match read_bit(buf, metadata)? {
-            BitValue::Zero => {
-                count = last_measurement.count;
-            },
-            BitValue::One => {
-                let count_delta = varint::decode_zigzag(read_varint(buf, metadata)?);
-                count = delta_add(last_measurement.count, count_delta);
-            }
+fn decode_count(buf: &[u8], metadata: &mut CodecMetadata, last_measurement: &Measurement) -> Result<(u64, Path), DecoderErrorKind>
+{
+    match read_bit(buf, metadata)? {
+        BitValue::Zero => Ok((last_measurement.count, Path::CountRepeat)),
+        BitValue::One => {
+            let count_delta = varint::decode_zigzag(read_varint(buf, metadata)?);
+            Ok((delta_add(last_measurement.count, count_delta), Path::CountDelta(count_delta)))
         }
+    }
+}
+
+fn decode_value(buf: &[u8], metadata: &mut CodecMetadata, last_measurement: &Measurement) -> Result<(f64, Path), DecoderErrorKind>
+{
+    if read_bit(buf, metadata)? == BitValue::Zero {
+        return Ok((last_measurement.value, Path::ValueRepeat));
+    }
+
+    let prev_value = last_measurement.value.to_bits();
+
+    match read_bit(buf, metadata)? {
+        BitValue::Zero => {
+            let xor = match metadata.value_xor {
+                // Should never happen
+                None => return Err(DecoderErrorKind::MissingXorWindow),
+                Some(xor) => xor
+            };
+
+            let zeros = (xor.leading_zeros(), xor.trailing_zeros());
+            let mut bytes = [0u8; 8];
 
-        let value;
-        match read_bit(buf, metadata)? {
-            BitValue::Zero => {
-                value = last_measurement.value
-            },
-            BitValue::One => {
-                match read_bit(buf, metadata)? {
-                    BitValue::Zero => {
-                        let xor = match metadata.value_xor {
-                            // Should never happen
-                            None => return Err(DecoderError::Generic("No previous xor value".to_string())),
-                            Some(xor) => xor
-                        };
-
-                        let zeros = (xor.leading_zeros(), xor.trailing_zeros());
-                        let mut bytes = [0u8; 8];
-
-                        read_bits(buf, metadata, &mut bytes, zeros.0 as usize, 64 - (zeros.0 + zeros.1) as usize)?;
-
-                        let prev_value = unsafe { double_to_int(last_measurement.value) };
-                        let curr_value = prev_value ^ u64::from_be_bytes(bytes);
-
-                        value = unsafe { int_to_double(curr_value) };
-                    },
-                    BitValue::One => {
-                        let mut leading_zeros = [0u8; 1];
-                        let mut sig_bits = [0u8; 1];
-
-                        read_bits(buf, metadata, &mut leading_zeros, 8 - 6, 6)?;
-                        read_bits(buf, metadata, &mut sig_bits, 8 - 6, 6)?;
-
-                        let mut bytes = [0u8; 8];
-                        read_bits(buf, metadata, &mut bytes, leading_zeros[0] as usize, sig_bits[0] as usize)?;
-
-                        let xor = u64::from_be_bytes(bytes);
-
-                        let prev_value = unsafe { double_to_int(last_measurement.value) };
-                        let curr_value = prev_value ^ xor;
-
-                        value = unsafe { int_to_double(curr_value) };
-                        metadata.value_xor = Some(xor);
-                    }
-                }
+            read_bits(buf, metadata, &mut bytes, zeros.0 as usize, 64 - (zeros.0 + zeros.1) as usize)?;
+
+            let path = Path::XorReuseWindow { leading: zeros.0, trailing: zeros.1 };
+            Ok((f64::from_bits(prev_value ^ u64::from_be_bytes(bytes)), path))
+        },
+        BitValue::One => {
+            let mut leading_zeros = [0u8; 1];
+            let mut sig_bits = [0u8; 1];
+
+            read_bits(buf, metadata, &mut leading_zeros, 8 - 6, 6)?;
+            read_bits(buf, metadata, &mut sig_bits, 8 - 6, 6)?;
+
+            // A non-zero xor has at least one significant bit, so the encoder
+            // stores all 64 as 0 to fit them in 6 bits
+            let sig_bits = if sig_bits[0] == 0 { 64 } else { sig_bits[0] as usize };
+
+            if leading_zeros[0] as usize + sig_bits > 64 {
+                return Err(DecoderErrorKind::InvalidXorWindow { leading: leading_zeros[0], sig_bits });
             }
+
+            let mut bytes = [0u8; 8];
+            read_bits(buf, metadata, &mut bytes, leading_zeros[0] as usize, sig_bits)?;
+
+            // The window is taken from the xor itself, so it must be non-zero for later
+            // values to reuse it. The encoder never writes a zero xor here.
+            let xor = u64::from_be_bytes(bytes);
+            if xor == 0 {
+                return Err(DecoderErrorKind::ZeroXorWindow);
+            }
+            metadata.value_xor = Some(xor);
+
+            let path = Path::XorNewWindow { leading: leading_zeros[0] as u32, sig_bits: sig_bits as u32 };
+            Ok((f64::from_bits(prev_value ^ xor), path))
         }
+    }
+}
 
-        measurement = Measurement{timestamp, count, value};
+// Reports each field to the caller's `on_field` as it's read, or where the field started if it can't be
+struct FieldReader<F> {
+    index: usize,
+    offset: usize,
+    on_field: F
+}
+
+impl<F: FnMut(&FieldTrace)> FieldReader<F> {
+    // Values are passed through as their bits
+    fn read<R>(&mut self, field: Field, metadata: &mut CodecMetadata, read: R) -> Result<u64, DecoderError>
+        where R: FnOnce(&mut CodecMetadata) -> Result<(u64, Path), DecoderErrorKind>
+    {
+        let (value, path) = read(metadata)
+            .map_err(|kind| DecoderError { index: self.index, offset: self.offset, field, kind })?;
+
+        (self.on_field)(&FieldTrace { field, offset: self.offset, nbits: metadata.buf_offbits - self.offset, path, value });
+        self.offset = metadata.buf_offbits;
+        Ok(value)
     }
+}
+
+// Decodes the next measurement, calling `on_field` with where and how each field was read
+pub fn decode_fields<F: FnMut(&FieldTrace)>(buf: &[u8], metadata: &mut CodecMetadata, on_field: F) -> Result<Measurement, DecoderError>
+{
+    let mut fields = FieldReader { index: metadata.idx, offset: metadata.buf_offbits, on_field };
+
+    let measurement = if metadata.idx == 0 {
+        let timestamp = fields.read(Field::Timestamp, metadata, |m| Ok((read_varint(buf, m)?, Path::First)))?;
+        let count = fields.read(Field::Count, metadata, |m| Ok((read_varint(buf, m)?, Path::First)))?;
+        let value = fields.read(Field::Value, metadata, |m| Ok((read_double(buf, m)?.to_bits(), Path::First)))?;
+
+        Measurement{timestamp, count, value: f64::from_bits(value)}
+    } else {
+        let last_measurement = metadata.last_measurement.ok_or(DecoderError {
+            index: metadata.idx, offset: metadata.buf_offbits, field: Field::Timestamp, kind: DecoderErrorKind::MissingPrevious
+        })?;
+
+        let timestamp = fields.read(Field::Timestamp, metadata, |m| decode_timestamp(buf, m, &last_measurement))?;
+        let count = fields.read(Field::Count, metadata, |m| decode_count(buf, m, &last_measurement))?;
+        let value = fields.read(Field::Value, metadata, |m| {
+            decode_value(buf, m, &last_measurement).map(|(value, path)| (value.to_bits(), path))
+        })?;
+
+        Measurement{timestamp, count, value: f64::from_bits(value)}
+    };
 
     metadata.last_measurement = Some(measurement);
     metadata.idx += 1;
 
     Ok(measurement)
-}
\ No newline at end of file
+}
+
+pub fn decode(buf: &[u8], metadata: &mut CodecMetadata) -> Result<Measurement, DecoderError>
+{
+    decode_fields(buf, metadata, |_| {})
+}
+
+pub struct Measurements<'a> {
+    buf: &'a [u8],
+    metadata: CodecMetadata,
+    remaining: usize
+}
+
+impl<'a> Iterator for Measurements<'a> {
+    type Item = Result<Measurement, DecoderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let result = decode(self.buf, &mut self.metadata);
+
+        // Nothing after a decode error can be trusted, so stop there
+        self.remaining = match result {
+            Ok(_) => self.remaining - 1,
+            Err(_) => 0
+        };
+
+        Some(result)
+    }
+}
+
+// Lazily decodes the first `count` measurements of `buf`, one per call to next(). The
+// count is not stored in the block, so callers pass along the encoder's measurement_count().
+pub fn measurements(buf: &[u8], count: usize) -> Measurements<'_>
+{
+    Measurements { buf, metadata: CodecMetadata::new(), remaining: count }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::utils::bitstream::BitWriter;
+
+    // Crafted inputs kept alongside the fuzz targets, for paths that used to panic or that
+    // relied on bitcopy to catch them
+
+    fn varint(writer: &mut BitWriter, value: u64) {
+        let mut buf = [0u8; 10];
+        let sz = varint::encode(value, &mut buf).unwrap();
+
+        for b in &buf[..sz] {
+            writer.write(*b as u64, 8).unwrap();
+        }
+    }
+
+    // A first measurement with a zero value
+    fn first(timestamp: u64) -> BitWriter {
+        let mut writer = BitWriter::new();
+        varint(&mut writer, timestamp);
+        varint(&mut writer, 0);
+        writer.write(0, 64).unwrap();
+        writer
+    }
+
+    fn decode_all(buf: &[u8], count: usize) -> Vec<Result<Measurement, DecoderError>> {
+        measurements(buf, count).collect()
+    }
+
+    #[test]
+    fn test_zero_xor_window()
+    {
+        // New window with a single significant bit that's zero, then a value reusing it
+        let mut writer = first(0);
+        varint(&mut writer, 0);
+        writer.write(0b0_1_1, 3).unwrap();
+        writer.write(0, 6).unwrap();
+        writer.write(1, 6).unwrap();
+        writer.write(0, 1).unwrap();
+        writer.write(0b0_0_1_0, 4).unwrap();
+
+        let results = decode_all(&writer.into_bytes(), 3);
+        assert!(results[0].is_ok());
+        assert_eq!(results[1], Err(DecoderError { index: 1, offset: 89, field: Field::Value, kind: DecoderErrorKind::ZeroXorWindow }));
+    }
+
+    #[test]
+    fn test_oversized_xor_window()
+    {
+        // 63 leading zeros and 64 significant bits
+        let mut writer = first(0);
+        varint(&mut writer, 0);
+        writer.write(0b0_1_1, 3).unwrap();
+        writer.write(63, 6).unwrap();
+        writer.write(0, 6).unwrap();
+        writer.write(u64::MAX, 64).unwrap();
+        writer.write(u64::MAX, 64).unwrap();
+
+        let results = decode_all(&writer.into_bytes(), 2);
+        let error = results[1].clone().unwrap_err();
+        assert_eq!(error.kind, DecoderErrorKind::InvalidXorWindow { leading: 63, sig_bits: 64 });
+        assert!(!error.is_truncated());
+    }
+
+    #[test]
+    fn test_timestamp_overflow()
+    {
+        // A delta past u64::MAX, then delta-of-deltas past i64::MAX
+        let mut writer = first(u64::MAX);
+        varint(&mut writer, varint::encode_zigzag(i64::MAX));
+        writer.write(0b0_0, 2).unwrap();
+        writer.write(1, 1).unwrap();
+        varint(&mut writer, varint::encode_zigzag(i64::MAX));
+        writer.write(0b0_0, 2).unwrap();
+        writer.write(0b0_0_0, 3).unwrap();
+
+        let results: Vec<Measurement> = decode_all(&writer.into_bytes(), 4).into_iter().map(Result::unwrap).collect();
+        assert_eq!(results[1].timestamp, (i64::MAX as u64).wrapping_add(u64::MAX));
+        assert_eq!(results.len(), 4);
+    }
+
+    #[test]
+    fn test_unterminated_varint()
+    {
+        let error = decode_all(&[0xff; 32], 1)[0].clone().unwrap_err();
+        assert_eq!((error.index, error.offset, error.field), (0, 0, Field::Timestamp));
+        assert_eq!(error.kind, DecoderErrorKind::UnterminatedVarint);
+        assert!(!error.is_truncated());
+
+        let error = decode_all(&[], 1)[0].clone().unwrap_err();
+        assert_eq!(error.kind, DecoderErrorKind::BitCopy(BitCopyError::SourceOverrun { offset: 0, nbits: 8, len: 0 }));
+        assert!(error.is_truncated());
+        assert_eq!(error.to_string(), "measurement 0: timestamp at bit 0: reading 8 bits at bit 0 overruns the 0 bit source");
+    }
+}
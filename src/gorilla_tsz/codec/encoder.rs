@@ -1,21 +1,68 @@
-
+use std::error;
+use std::fmt;
 use std::mem;
 
 use super::CodecMetadata;
+use super::Field;
 use super::Measurement;
-use super::double_to_int;
 use super::super::utils::bitcopy;
-use super::super::utils::bitcopy::BitValue;
+use super::super::utils::bitcopy::{BitCopyError, BitValue};
 use super::super::utils::varint;
 
-pub enum EncoderError {
-    Generic,
-    BitCopyError(bitcopy::BitCopyError)
+// Worst case size of an encoded measurement: three varints, their control bits and a
+// value with a new xor window
+pub const MAX_MEASUREMENT_BYTES: usize = 3 * 10 + 1 + (12 + 64) / 8 + 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EncoderErrorKind {
+    // Usually the buffer is full
+    BitCopy(BitCopyError),
+    // The encoder state has no previous measurement
+    MissingPrevious
+}
+
+impl From<BitCopyError> for EncoderErrorKind {
+    fn from(e: BitCopyError) -> Self {
+        EncoderErrorKind::BitCopy(e)
+    }
+}
+
+impl fmt::Display for EncoderErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncoderErrorKind::BitCopy(e) => write!(f, "{}", e),
+            EncoderErrorKind::MissingPrevious => write!(f, "no previous measurement")
+        }
+    }
+}
+
+// A failure to encode a field, with the measurement it belongs to and the bit it would start at
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncoderError {
+    pub index: usize,
+    pub offset: usize,
+    pub field: Field,
+    pub kind: EncoderErrorKind
+}
+
+impl EncoderError {
+    pub fn is_buffer_full(&self) -> bool {
+        matches!(self.kind, EncoderErrorKind::BitCopy(BitCopyError::DestinationOverrun { .. }))
+    }
 }
 
-impl From<bitcopy::BitCopyError> for EncoderError {
-    fn from(e: bitcopy::BitCopyError) -> Self {
-        EncoderError::BitCopyError(e)
+impl fmt::Display for EncoderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "measurement {}: {} at bit {}: {}", self.index, self.field, self.offset, self.kind)
+    }
+}
+
+impl error::Error for EncoderError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.kind {
+            EncoderErrorKind::BitCopy(e) => Some(e),
+            EncoderErrorKind::MissingPrevious => None
+        }
     }
 }
 
@@ -23,7 +70,7 @@ fn to_bytes(val: u64) -> [u8; 8] {
     val.to_be_bytes()
 }
 
-fn write_bits(buf: &mut [u8], metadata: &mut CodecMetadata, src: &[u8], src_offbits: usize, nbits: usize) -> Result<(), EncoderError>
+fn write_bits(buf: &mut [u8], metadata: &mut CodecMetadata, src: &[u8], src_offbits: usize, nbits: usize) -> Result<(), EncoderErrorKind>
 {
     bitcopy::copy(buf, src, nbits, metadata.buf_offbits, src_offbits)?;
 
@@ -31,7 +78,7 @@ fn write_bits(buf: &mut [u8], metadata: &mut CodecMetadata, src: &[u8], src_offb
     Ok(())
 }
 
-fn write_varint(buf: &mut [u8], metadata: &mut CodecMetadata, value: u64) -> Result<(), EncoderError>
+fn write_varint(buf: &mut [u8], metadata: &mut CodecMetadata, value: u64) -> Result<(), EncoderErrorKind>
 {
     let mut varint_buf = [0u8; 10];
 
@@ -40,16 +87,16 @@ fn write_varint(buf: &mut [u8], metadata: &mut CodecMetadata, value: u64) -> Res
     write_bits(buf, metadata, &varint_buf, 0, sz * 8)
 }
 
-fn write_double(buf: &mut [u8], metadata: &mut CodecMetadata, value: f64) -> Result<(), EncoderError>
+fn write_double(buf: &mut [u8], metadata: &mut CodecMetadata, value: f64) -> Result<(), EncoderErrorKind>
 {
-    let int_val = unsafe { double_to_int(value) };
+    let int_val = value.to_bits();
     let bytes = to_bytes(int_val);
     let nbits = mem::size_of::<f64>() * 8;
 
     write_bits(buf, metadata, &bytes, 0, nbits)
 }
 
-fn write_bit(buf: &mut [u8], metadata: &mut CodecMetadata, value: BitValue) -> Result<(), EncoderError>
+fn write_bit(buf: &mut [u8], metadata: &mut CodecMetadata, value: BitValue) -> Result<(), EncoderErrorKind>
 {
     bitcopy::write_bit(buf, metadata.buf_offbits, value)?;
     metadata.buf_offbits += 1;
@@ -57,101 +104,118 @@ fn write_bit(buf: &mut [u8], metadata: &mut CodecMetadata, value: BitValue) -> R
 }
 
 
-pub fn encode(buf: &mut [u8], metadata: &mut CodecMetadata, measurement: &Measurement) -> Result<(), EncoderError>
+fn encode_timestamp(buf: &mut [u8], metadata: &mut CodecMetadata, last_measurement: &Measurement, timestamp: u64) -> Result<(), EncoderErrorKind>
 {
-    // Write first
-    if metadata.idx == 0 {
-        write_varint(buf, metadata, measurement.timestamp)?;
-        write_varint(buf, metadata, measurement.count)?;
-        write_double(buf, metadata, measurement.value)?;
+    // Deltas wrap around, so any pair of u64s has one and the decoder gets back the same value
+    let timestamp_delta = timestamp.wrapping_sub(last_measurement.timestamp) as i64;
 
-        metadata.last_timestamp_delta = 0;
+    if metadata.idx == 1 {
+        write_varint(buf, metadata, varint::encode_zigzag(timestamp_delta))?;
     } else {
-        let last_measurement = match metadata.last_measurement {
-            None => return Err(EncoderError::Generic),
-            Some(measure) => measure
-        };
-
-        let timestamp_delta = measurement.timestamp as i64 - last_measurement.timestamp as i64;
+        let timestamp_delta2 = timestamp_delta.wrapping_sub(metadata.last_timestamp_delta);
 
-        if metadata.idx == 1 {
-            write_varint(buf, metadata, varint::encode_zigzag(timestamp_delta))?;
+        if timestamp_delta2 == 0 {
+            write_bit(buf, metadata, BitValue::Zero)?;
         } else {
-            let timestamp_delta2 = timestamp_delta - metadata.last_timestamp_delta;
-
-            if timestamp_delta2 == 0 {
-                write_bit(buf, metadata, BitValue::Zero)?;
-            } else {
-                write_bit(buf, metadata, BitValue::One)?;
-                write_varint(buf, metadata, varint::encode_zigzag(timestamp_delta2))?;
-            }
+            write_bit(buf, metadata, BitValue::One)?;
+            write_varint(buf, metadata, varint::encode_zigzag(timestamp_delta2))?;
         }
+    }
+
+    metadata.last_timestamp_delta = timestamp_delta;
+    Ok(())
+}
 
-        let count_delta = measurement.count as i64 - last_measurement.count as i64;
+fn encode_count(buf: &mut [u8], metadata: &mut CodecMetadata, last_measurement: &Measurement, count: u64) -> Result<(), EncoderErrorKind>
+{
+    let count_delta = count.wrapping_sub(last_measurement.count) as i64;
 
-        if count_delta == 0 {
-            write_bit(buf, metadata, BitValue::Zero)?;
-        } else {
-            write_bit(buf, metadata, BitValue::One)?;
+    if count_delta == 0 {
+        write_bit(buf, metadata, BitValue::Zero)
+    } else {
+        write_bit(buf, metadata, BitValue::One)?;
 
-            // TODO: look at alternatives to varint encoding that assume small count deltas
-            // ...the FB Gorilla paper did an analysis of their data to reduce here
-            write_varint(buf, metadata, varint::encode_zigzag(count_delta))?;
-        }
+        // TODO: look at alternatives to varint encoding that assume small count deltas
+        // ...the FB Gorilla paper did an analysis of their data to reduce here
+        write_varint(buf, metadata, varint::encode_zigzag(count_delta))
+    }
+}
 
-        let value_a = unsafe { double_to_int(last_measurement.value) };
-        let value_b = unsafe { double_to_int(measurement.value) };
+fn encode_value(buf: &mut [u8], metadata: &mut CodecMetadata, last_measurement: &Measurement, value: f64) -> Result<(), EncoderErrorKind>
+{
+    let value_a = last_measurement.value.to_bits();
+    let value_b = value.to_bits();
 
-        let xor = value_a ^ value_b;
+    let xor = value_a ^ value_b;
 
-        if xor == 0 {
-            write_bit(buf, metadata, BitValue::Zero)?;
-        } else {
-            write_bit(buf, metadata, BitValue::One)?;
+    if xor == 0 {
+        return write_bit(buf, metadata, BitValue::Zero);
+    }
 
-            let curr_zeros = (xor.leading_zeros(), xor.trailing_zeros());
+    write_bit(buf, metadata, BitValue::One)?;
 
-            println!("xor zeros: {:?} ({})\t-\t{:64b}", curr_zeros, (curr_zeros.0 + curr_zeros.1), xor);
+    let curr_zeros = (xor.leading_zeros(), xor.trailing_zeros());
 
-            let mut written = false;
-            if let Some(prev_xor) = metadata.value_xor {
-                let prev_zeros = (prev_xor.leading_zeros(), prev_xor.trailing_zeros());
+    if let Some(prev_xor) = metadata.value_xor {
+        let prev_zeros = (prev_xor.leading_zeros(), prev_xor.trailing_zeros());
 
-                if curr_zeros.0 >= prev_zeros.0 && curr_zeros.1 >= prev_zeros.1 {
-                    println!("Using previous zeros of {:?} ({})", prev_zeros, (prev_zeros.0 + prev_zeros.1));
+        if curr_zeros.0 >= prev_zeros.0 && curr_zeros.1 >= prev_zeros.1 {
+            write_bit(buf, metadata, BitValue::Zero)?;
 
-                    write_bit(buf, metadata, BitValue::Zero)?;
+            let fbytes = to_bytes(xor);
+            let bits = 64 - (prev_zeros.0 + prev_zeros.1);
 
-                    let fbytes = to_bytes(xor);
-                    let bits = 64 - (prev_zeros.0 + prev_zeros.1);
+            return write_bits(buf, metadata, &fbytes, prev_zeros.0 as usize, bits as usize);
+        }
+    }
 
-                    write_bits(buf, metadata, &fbytes, prev_zeros.0 as usize, bits as usize)?;
-                    written = true;
-                }
-            }
+    write_bit(buf, metadata, BitValue::One)?;
+    let lead_zero_bits = [curr_zeros.0 as u8];
+    let sig_bits = 64 - (curr_zeros.0 + curr_zeros.1) as usize;
 
-            if !written {
-                println!("Not encoded..writing new xor with zeros: {:?}", curr_zeros);
+    // 64 significant bits don't fit in 6, they're written as 0 instead
+    write_bits(buf, metadata, &lead_zero_bits, 8 - 6, 6)?;
+    write_bits(buf, metadata, &[(sig_bits % 64) as u8], 8 - 6, 6)?;
 
-                write_bit(buf, metadata, BitValue::One)?;
-                let lead_zero_bits = [curr_zeros.0 as u8];
-                let sig_bits = [64 - (curr_zeros.0 + curr_zeros.1) as u8];
+    let fbytes = to_bytes(xor);
+    write_bits(buf, metadata, &fbytes, curr_zeros.0 as usize, sig_bits)?;
+    metadata.value_xor = Some(xor);
+    Ok(())
+}
 
-                write_bits(buf, metadata, &lead_zero_bits, 8 - 6, 6)?;
-                write_bits(buf, metadata, &sig_bits, 8 - 6, 6)?;
+pub fn encode(buf: &mut [u8], metadata: &mut CodecMetadata, measurement: &Measurement) -> Result<(), EncoderError>
+{
+    let index = metadata.idx;
+    let mut offset = metadata.buf_offbits;
 
-                let fbytes = to_bytes(xor);
-                write_bits(buf, metadata, &fbytes, curr_zeros.0 as usize, sig_bits[0] as usize)?;
-                metadata.value_xor = Some(xor);
-            }
-        }
+    // Tags a failed field with the bit it started at
+    let mut written = |field, result: Result<(), EncoderErrorKind>, metadata: &CodecMetadata| {
+        result.map_err(|kind| EncoderError { index, offset, field, kind })?;
+        offset = metadata.buf_offbits;
+        Ok::<(), EncoderError>(())
+    };
 
-        metadata.last_timestamp_delta = timestamp_delta;
+    // Write first
+    if metadata.idx == 0 {
+        written(Field::Timestamp, write_varint(buf, metadata, measurement.timestamp), metadata)?;
+        written(Field::Count, write_varint(buf, metadata, measurement.count), metadata)?;
+        written(Field::Value, write_double(buf, metadata, measurement.value), metadata)?;
 
+        metadata.last_timestamp_delta = 0;
+    } else {
+        let last_measurement = match metadata.last_measurement {
+            None => return Err(EncoderError { index, offset: metadata.buf_offbits, field: Field::Timestamp,
+                                              kind: EncoderErrorKind::MissingPrevious }),
+            Some(measure) => measure
+        };
+
+        written(Field::Timestamp, encode_timestamp(buf, metadata, &last_measurement, measurement.timestamp), metadata)?;
+        written(Field::Count, encode_count(buf, metadata, &last_measurement, measurement.count), metadata)?;
+        written(Field::Value, encode_value(buf, metadata, &last_measurement, measurement.value), metadata)?;
     }
 
     metadata.last_measurement = Some(*measurement);
     metadata.idx += 1;
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,158 @@
+// Annotated decoding, for working out what went wrong in a block. Every field is reported
+// with its bit offset, its raw bits, the control path it was encoded with and its value.
+// Fields of the measurement that failed to decode are kept, up to the one in error.
+
+use std::io;
+use std::io::Write;
+
+use super::CodecMetadata;
+use super::Measurement;
+use super::decoder;
+use super::decoder::{DecoderError, Field, FieldTrace};
+use super::super::utils::bitcopy;
+use super::super::utils::bitcopy::BitValue;
+
+pub struct Row {
+    pub index: usize,
+    pub trace: FieldTrace,
+    // None for the fields read before an error
+    pub measurement: Option<Measurement>
+}
+
+pub struct Explanation {
+    pub rows: Vec<Row>,
+    pub error: Option<DecoderError>
+}
+
+// Decodes up to `count` measurements, stopping at the first error
+pub fn explain(buf: &[u8], count: usize) -> Explanation
+{
+    let mut metadata = CodecMetadata::new();
+    let mut rows = Vec::new();
+
+    for index in 0..count {
+        let mut traces = Vec::new();
+        let result = decoder::decode_fields(buf, &mut metadata, |trace| traces.push(*trace));
+
+        let measurement = result.as_ref().ok().copied();
+        rows.extend(traces.into_iter().map(|trace| Row { index, trace, measurement }));
+
+        if let Err(error) = result {
+            return Explanation { rows, error: Some(error) };
+        }
+    }
+
+    Explanation { rows, error: None }
+}
+
+// The bits of a field as 0s and 1s, with its control bits split off by spaces
+pub fn raw_bits(buf: &[u8], trace: &FieldTrace) -> String
+{
+    let mut bits = String::with_capacity(trace.nbits + 8);
+    let mut breaks = Vec::new();
+
+    let mut end = 0;
+    for width in trace.path.control_bits() {
+        end += width;
+        breaks.push(end);
+    }
+
+    for i in 0..trace.nbits {
+        if breaks.contains(&i) {
+            bits.push(' ');
+        }
+
+        bits.push(match bitcopy::read_bit(buf, trace.offset + i) {
+            Ok(BitValue::One) => '1',
+            Ok(BitValue::Zero) => '0',
+            Err(_) => '?'
+        });
+    }
+
+    bits
+}
+
+fn field_value(trace: &FieldTrace) -> String
+{
+    match trace.field {
+        Field::Timestamp | Field::Count => trace.value.to_string(),
+        Field::Value => format!("{} ({:#018x})", f64::from_bits(trace.value), trace.value)
+    }
+}
+
+pub fn write_table(buf: &[u8], explanation: &Explanation, out: &mut dyn Write) -> io::Result<()>
+{
+    writeln!(out, "{:>6} {:<9} {:>8} {:>5}  {:<44} {:<44} raw", "#", "field", "offset", "bits", "path", "value")?;
+
+    for row in &explanation.rows {
+        writeln!(out, "{:>6} {:<9} {:>8} {:>5}  {:<44} {:<44} {}", row.index, row.trace.field.to_string(), row.trace.offset, row.trace.nbits,
+                 row.trace.path.to_string(), field_value(&row.trace), raw_bits(buf, &row.trace))?;
+    }
+
+    if let Some(e) = &explanation.error {
+        writeln!(out, "error decoding {}", e)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::encoder::encode;
+    use super::super::decoder::Path;
+
+    #[test]
+    fn test_explain()
+    {
+        let mut buf = [0u8; 128];
+        let mut metadata = CodecMetadata::new();
+        let measures = [Measurement{timestamp: 100, count: 1, value: 1.0},
+                        Measurement{timestamp: 110, count: 1, value: 1.0},
+                        Measurement{timestamp: 120, count: 2, value: 2.0},
+                        Measurement{timestamp: 125, count: 2, value: 4.0}];
+
+        for m in measures.iter() {
+            encode(&mut buf, &mut metadata, m).ok().unwrap();
+        }
+
+        let explanation = explain(&buf[..metadata.byte_len()], measures.len());
+        assert!(explanation.error.is_none());
+        assert_eq!(explanation.rows.len(), 12);
+
+        let paths: Vec<Path> = explanation.rows.iter().map(|r| r.trace.path).collect();
+        assert_eq!(paths, vec![Path::First, Path::First, Path::First,
+                               Path::TimestampDelta(10), Path::CountRepeat, Path::ValueRepeat,
+                               Path::DeltaOfDeltaZero, Path::CountDelta(1), Path::XorNewWindow { leading: 1, sig_bits: 11 },
+                               Path::DeltaOfDelta(-5), Path::CountRepeat, Path::XorReuseWindow { leading: 1, trailing: 52 }]);
+
+        // Fields are contiguous
+        for pair in explanation.rows.windows(2) {
+            assert_eq!(pair[0].trace.offset + pair[0].trace.nbits, pair[1].trace.offset);
+        }
+
+        // 1.0 ^ 2.0 has 1 leading zero and 11 significant bits
+        assert_eq!(raw_bits(&buf, &explanation.rows[8].trace), "1 1 000001 001011 11111111111");
+        assert_eq!(raw_bits(&buf, &explanation.rows[3].trace), "00010100");
+
+        let mut out = Vec::new();
+        write_table(&buf, &explanation, &mut out).unwrap();
+        let table = String::from_utf8(out).unwrap();
+        assert_eq!(table.lines().count(), 13);
+        assert!(table.contains("xor new window (1 leading, 11 significant)"), "{}", table);
+
+        let truncated = explain(&buf[..6], measures.len());
+        let error = truncated.error.unwrap();
+        assert_eq!((error.index, error.offset, error.field), (0, 16, Field::Value));
+        assert!(error.is_truncated());
+
+        // The timestamp and count that did decode are kept
+        let fields: Vec<(Field, u64, Option<Measurement>)> = truncated.rows.iter().map(|r| (r.trace.field, r.trace.value, r.measurement)).collect();
+        assert_eq!(fields, vec![(Field::Timestamp, 100, None), (Field::Count, 1, None)]);
+
+        let truncated = explain(&buf[..metadata.byte_len() - 1], measures.len());
+        assert_eq!(truncated.rows.len(), 11);
+        assert_eq!(truncated.rows[9].measurement, None);
+        assert_eq!(truncated.rows[8].measurement, Some(measures[2]));
+        assert_eq!(truncated.error.unwrap().field, Field::Value);
+    }
+}
@@ -0,0 +1,439 @@
+// M3TSZ segments (m3db/src/dbnode/encoding/m3tsz), M3DB's variant of the Gorilla encoding.
+// A segment starts with the block start in 64 bits of Unix nanoseconds. Timestamps follow
+// as delta-of-deltas from there, in seconds, using M3's seconds scheme: a 0 bit for no
+// change, else 7/9/12/32 bit two's complement values behind 10/110/1110/1111 opcodes. Values
+// are the first float in full, then XORs: 0 for none, 10 to reuse the last XOR's window and
+// 11 for a new one, with 6 bits of leading zeros and 6 of meaningful bits less one.
+//
+// In-stream markers hide behind the 10 opcode with an impossible zero value: 100000000
+// followed by 2 bits for end of stream, an annotation or a time unit change. Annotations are
+// skipped on decode. Only segments in seconds written with M3's int optimization turned off
+// are supported, and as with Beringei there is no per-point count: decoded measurements get
+// a count of 1 and encoding ignores it. Timestamps are Unix seconds.
+
+use std::error;
+use std::fmt;
+
+use super::Measurement;
+use super::super::utils::bitcopy::{BitCopyError, BitValue};
+use super::super::utils::bitstream::{BitReader, BitWriter};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SegmentError {
+    BitCopy(BitCopyError),
+    // The block start, in nanoseconds as on the wire, must be whole seconds within an i64
+    InvalidStart(u64),
+    // Timestamps must be whole seconds that fit an i64 in nanoseconds
+    TimestampOutOfRange { index: usize, timestamp: i64 },
+    DeltaOfDeltaOutOfRange { index: usize, dod: i64 },
+    InvalidXorWindow { index: usize, leading: u32, bits: u32 },
+    InvalidAnnotation { index: usize },
+    // Switching away from seconds isn't supported
+    UnsupportedTimeUnit(u8)
+}
+
+impl SegmentError {
+    // The segment ended before its end of stream marker
+    pub fn is_truncated(&self) -> bool {
+        matches!(self, SegmentError::BitCopy(BitCopyError::SourceOverrun { .. }))
+    }
+}
+
+impl From<BitCopyError> for SegmentError {
+    fn from(e: BitCopyError) -> Self {
+        SegmentError::BitCopy(e)
+    }
+}
+
+impl fmt::Display for SegmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SegmentError::BitCopy(e) => write!(f, "{}", e),
+            SegmentError::InvalidStart(start) => write!(f, "invalid block start: {}ns", start),
+            SegmentError::TimestampOutOfRange { index, timestamp } => write!(f, "timestamp out of range in point {}: {}", index, timestamp),
+            SegmentError::DeltaOfDeltaOutOfRange { index, dod } => write!(f, "timestamp delta-of-delta out of range in point {}: {}", index, dod),
+            SegmentError::InvalidXorWindow { index, leading, bits } =>
+                write!(f, "invalid xor window in point {}: {} leading zeros, {} bits", index, leading, bits),
+            SegmentError::InvalidAnnotation { index } => write!(f, "invalid annotation before point {}", index),
+            SegmentError::UnsupportedTimeUnit(unit) => write!(f, "unsupported time unit {}", unit)
+        }
+    }
+}
+
+impl error::Error for SegmentError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SegmentError::BitCopy(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const MAX_SECONDS: i64 = i64::MAX / NANOS_PER_SECOND as i64;
+
+const MARKER_OPCODE: u64 = 0x100;
+const MARKER_OPCODE_BITS: usize = 9;
+const MARKER_VALUE_BITS: usize = 2;
+const END_OF_STREAM: u64 = 0;
+const ANNOTATION: u64 = 1;
+const TIME_UNIT: u64 = 2;
+
+const LEADING_ZEROS_BITS: usize = 6;
+const MEANINGFUL_BITS_BITS: usize = 6;
+
+// Delta-of-delta buckets, as (value bits, opcode, opcode length)
+const DOD_BUCKETS: [(usize, u64, usize); 4] = [(7, 0b10, 2), (9, 0b110, 3), (12, 0b1110, 4), (32, 0b1111, 4)];
+
+// M3 treats an xor of 0 as 64 leading zeros
+fn leading_and_trailing_zeros(xor: u64) -> (u32, u32)
+{
+    if xor == 0 { (64, 0) } else { (xor.leading_zeros(), xor.trailing_zeros()) }
+}
+
+fn sign_extend(value: u64, nbits: usize) -> i64
+{
+    ((value << (64 - nbits)) as i64) >> (64 - nbits)
+}
+
+// `start` is the block start in seconds, which the first timestamp is encoded against
+pub fn encode_segment(start: u64, measurements: &[Measurement]) -> Result<Vec<u8>, SegmentError>
+{
+    if start > MAX_SECONDS as u64 {
+        return Err(SegmentError::InvalidStart(start.saturating_mul(NANOS_PER_SECOND)));
+    }
+
+    let mut writer = BitWriter::new();
+    writer.write(start * NANOS_PER_SECOND, 64)?;
+
+    let mut prev_timestamp = start as i64;
+    let mut prev_delta = 0i64;
+    let mut prev_value = 0u64;
+    let mut prev_xor = 0u64;
+
+    for (idx, measurement) in measurements.iter().enumerate() {
+        // Past i64::MAX is still out of range, so it's saturated for reporting
+        let timestamp = measurement.timestamp.min(i64::MAX as u64) as i64;
+
+        if timestamp > MAX_SECONDS {
+            return Err(SegmentError::TimestampOutOfRange { index: idx, timestamp });
+        }
+
+        let delta = timestamp - prev_timestamp;
+        let dod = delta - prev_delta;
+
+        if dod == 0 {
+            writer.write_bit(BitValue::Zero)?;
+        } else {
+            let &(nbits, opcode, opcode_bits) = DOD_BUCKETS.iter()
+                .find(|&&(nbits, _, _)| (-(1i64 << (nbits - 1))..1 << (nbits - 1)).contains(&dod))
+                .ok_or(SegmentError::DeltaOfDeltaOutOfRange { index: idx, dod })?;
+
+            writer.write(opcode, opcode_bits)?;
+            writer.write(dod as u64, nbits)?;
+        }
+
+        prev_timestamp = timestamp;
+        prev_delta = delta;
+
+        let value = measurement.value.to_bits();
+
+        if idx == 0 {
+            // The first value doubles as the xor the second is compared against
+            writer.write(value, 64)?;
+            prev_xor = value;
+        } else {
+            let xor = prev_value ^ value;
+
+            if xor == 0 {
+                writer.write_bit(BitValue::Zero)?;
+            } else {
+                let (prev_leading, prev_trailing) = leading_and_trailing_zeros(prev_xor);
+                let (leading, trailing) = leading_and_trailing_zeros(xor);
+
+                if leading >= prev_leading && trailing >= prev_trailing {
+                    writer.write(0b10, 2)?;
+                    writer.write(xor >> prev_trailing, (64 - prev_leading - prev_trailing) as usize)?;
+                } else {
+                    let bits = 64 - leading - trailing;
+
+                    writer.write(0b11, 2)?;
+                    writer.write(leading as u64, LEADING_ZEROS_BITS)?;
+                    writer.write((bits - 1) as u64, MEANINGFUL_BITS_BITS)?;
+                    writer.write(xor >> trailing, bits as usize)?;
+                }
+            }
+
+            prev_xor = xor;
+        }
+
+        prev_value = value;
+    }
+
+    writer.write(MARKER_OPCODE, MARKER_OPCODE_BITS)?;
+    writer.write(END_OF_STREAM, MARKER_VALUE_BITS)?;
+
+    Ok(writer.into_bytes())
+}
+
+// An annotation is a zigzag varint of its length less one, then the bytes themselves
+fn skip_annotation(reader: &mut BitReader, index: usize) -> Result<(), SegmentError>
+{
+    let mut len = 0u64;
+
+    for shift in (0..70).step_by(7) {
+        let byte = reader.read(8)?;
+        len |= (byte & 0x7f) << shift;
+
+        if byte < 0x80 {
+            let len = ((len >> 1) as i64 ^ -((len & 1) as i64)) + 1;
+
+            for _ in 0..len {
+                reader.read(8)?;
+            }
+            return Ok(());
+        }
+    }
+
+    Err(SegmentError::InvalidAnnotation { index })
+}
+
+// Reads the delta-of-delta of the next timestamp, skipping any annotations before it.
+// Returns None at the end of the segment.
+fn read_dod(reader: &mut BitReader, index: usize) -> Result<Option<i64>, SegmentError>
+{
+    // A marker that would run past the end of the input can't be one
+    while let Ok(bits) = reader.peek(MARKER_OPCODE_BITS + MARKER_VALUE_BITS) {
+        let marker = bits & ((1 << MARKER_VALUE_BITS) - 1);
+
+        if bits >> MARKER_VALUE_BITS != MARKER_OPCODE || marker > TIME_UNIT {
+            break;
+        }
+
+        reader.read(MARKER_OPCODE_BITS + MARKER_VALUE_BITS)?;
+
+        match marker {
+            END_OF_STREAM => return Ok(None),
+            ANNOTATION => skip_annotation(reader, index)?,
+            _ => return Err(SegmentError::UnsupportedTimeUnit(reader.read(8)? as u8))
+        }
+    }
+
+    let mut opcode = 0;
+    while opcode < DOD_BUCKETS.len() && reader.read_bit()? == BitValue::One {
+        opcode += 1;
+    }
+
+    if opcode == 0 {
+        return Ok(Some(0));
+    }
+
+    let nbits = DOD_BUCKETS[opcode - 1].0;
+    Ok(Some(sign_extend(reader.read(nbits)?, nbits)))
+}
+
+// Decodes up to the end of stream marker. An empty segment, as M3 writes for a block with
+// no points, decodes to nothing.
+pub fn decode_segment(data: &[u8]) -> Result<Vec<Measurement>, SegmentError>
+{
+    let mut reader = BitReader::new(data);
+    let mut measurements = Vec::new();
+
+    if data.is_empty() {
+        return Ok(measurements);
+    }
+
+    let start = reader.read(64)?;
+    if start > i64::MAX as u64 || start % NANOS_PER_SECOND != 0 {
+        return Err(SegmentError::InvalidStart(start));
+    }
+
+    let mut timestamp = (start / NANOS_PER_SECOND) as i64;
+    let mut delta = 0i64;
+    let mut value = 0u64;
+    let mut xor = 0u64;
+
+    loop {
+        let idx = measurements.len();
+
+        let dod = match read_dod(&mut reader, idx)? {
+            Some(dod) => dod,
+            None => break
+        };
+
+        delta = delta.wrapping_add(dod);
+        timestamp = timestamp.wrapping_add(delta);
+
+        if !(0..=MAX_SECONDS).contains(&timestamp) {
+            return Err(SegmentError::TimestampOutOfRange { index: idx, timestamp });
+        }
+
+        if idx == 0 {
+            value = reader.read(64)?;
+            xor = value;
+        } else {
+            xor = if reader.read_bit()? == BitValue::Zero {
+                0
+            } else if reader.read_bit()? == BitValue::Zero {
+                // A window of nothing after an xor of 0 reads as another 0, as in M3
+                let (leading, trailing) = leading_and_trailing_zeros(xor);
+                if xor == 0 { 0 } else { reader.read((64 - leading - trailing) as usize)? << trailing }
+            } else {
+                let leading = reader.read(LEADING_ZEROS_BITS)? as u32;
+                let bits = reader.read(MEANINGFUL_BITS_BITS)? as u32 + 1;
+
+                let trailing = 64u32.checked_sub(leading + bits)
+                    .ok_or(SegmentError::InvalidXorWindow { index: idx, leading, bits })?;
+
+                reader.read(bits as usize)? << trailing
+            };
+
+            value ^= xor;
+        }
+
+        measurements.push(Measurement { timestamp: timestamp as u64, count: 1, value: f64::from_bits(value) });
+    }
+
+    Ok(measurements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u64 = 1567029600;
+
+    fn fixture_measurements() -> Vec<Measurement> {
+        [(START + 60, 1.0), (START + 120, 1.0), (START + 181, 2.0), (START + 242, 3.0), (START + 272, 2.0), (START + 1272, 2.0)]
+            .iter()
+            .map(|&(timestamp, value)| Measurement{timestamp, count: 1, value})
+            .collect()
+    }
+
+    // Built field by field from M3's encoder, with an optional annotation before the first point
+    fn fixture(annotation: &[u8]) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        let mut write = |value: u64, nbits: usize| w.write(value, nbits).unwrap();
+
+        write(START * NANOS_PER_SECOND, 64);
+
+        if !annotation.is_empty() {
+            write(0x100, 9);
+            write(0b01, 2);
+            write(((annotation.len() - 1) * 2) as u64, 8);
+            for &b in annotation {
+                write(b as u64, 8);
+            }
+        }
+
+        // +60s from the start, dod 60; 1.0 in full
+        write(0b10, 2);
+        write(60, 7);
+        write(0x3ff0000000000000, 64);
+        // +60s, dod 0; 1.0 again, xor 0
+        write(0b0, 1);
+        write(0b0, 1);
+        // +61s, dod 1; 2.0, xor 0x7ff0000000000000 in a new window: 1 leading zero, 11 bits
+        write(0b10, 2);
+        write(1, 7);
+        write(0b11, 2);
+        write(1, 6);
+        write(10, 6);
+        write(0x7ff, 11);
+        // +61s, dod 0; 3.0, xor 0x0008000000000000 in a new window: 12 leading zeros, 1 bit
+        write(0b0, 1);
+        write(0b11, 2);
+        write(12, 6);
+        write(0, 6);
+        write(0b1, 1);
+        // +30s, dod -31; 2.0, the same xor in the previous window
+        write(0b10, 2);
+        write(0b1100001, 7);
+        write(0b10, 2);
+        write(0b1, 1);
+        // +1000s, dod 970 in the 12 bit bucket; 2.0 again
+        write(0b1110, 4);
+        write(970, 12);
+        write(0b0, 1);
+        // End of stream
+        write(0x100, 9);
+        write(0b00, 2);
+
+        w.into_bytes()
+    }
+
+    #[test]
+    fn test_fixture()
+    {
+        assert_eq!(decode_segment(&fixture(&[])).unwrap(), fixture_measurements());
+        assert_eq!(encode_segment(START, &fixture_measurements()).unwrap(), fixture(&[]));
+        assert_eq!(decode_segment(&fixture(b"\x0a\x03gauge")).unwrap(), fixture_measurements());
+    }
+
+    #[test]
+    fn test_roundtrip()
+    {
+        let mut measures = Vec::new();
+        let mut timestamp = START;
+
+        for (i, jitter) in [0i64, 63, -64, 255, -256, 2047, -2048, 100000, -100000, 1 << 30, 0].iter().enumerate() {
+            timestamp = (timestamp as i64 + 60 + jitter) as u64;
+            measures.push(Measurement{timestamp, count: 1, value: 43.568 + (i as f64 * 0.0023456)});
+        }
+
+        measures.push(Measurement{timestamp: timestamp + 10, count: 1, value: -1.0000000000000002});
+        measures.push(Measurement{timestamp: timestamp + 20, count: 1, value: f64::from_bits(0x7ff0000000000002)});
+        measures.push(Measurement{timestamp: timestamp + 30, count: 1, value: 0.0});
+        measures.push(Measurement{timestamp: timestamp + 40, count: 1, value: 0.0});
+        measures.push(Measurement{timestamp: timestamp + 50, count: 1, value: -0.0});
+
+        let segment = encode_segment(START, &measures).unwrap();
+        let decoded = decode_segment(&segment).unwrap();
+
+        assert_eq!(decoded.len(), measures.len());
+        for (a, b) in measures.iter().zip(decoded.iter()) {
+            assert_eq!(a.timestamp, b.timestamp);
+            assert_eq!(a.value.to_bits(), b.value.to_bits());
+        }
+
+        assert_eq!(decode_segment(&encode_segment(START, &[]).unwrap()).unwrap(), vec![]);
+        assert_eq!(decode_segment(&[]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_invalid_segments()
+    {
+        assert_eq!(encode_segment(u64::MAX, &[]), Err(SegmentError::InvalidStart(u64::MAX)));
+        assert_eq!(encode_segment(0, &[Measurement{timestamp: u64::MAX, count: 1, value: 0.0}]),
+                   Err(SegmentError::TimestampOutOfRange { index: 0, timestamp: i64::MAX }));
+        assert_eq!(encode_segment(0, &[Measurement{timestamp: 1 << 31, count: 1, value: 0.0}]),
+                   Err(SegmentError::DeltaOfDeltaOutOfRange { index: 0, dod: 1 << 31 }));
+
+        // Cut before the end of stream marker
+        let segment = fixture(&[]);
+        assert!(decode_segment(&segment[..segment.len() - 2]).unwrap_err().is_truncated());
+
+        assert_eq!(decode_segment(&(START * NANOS_PER_SECOND + 1).to_be_bytes()),
+                   Err(SegmentError::InvalidStart(START * NANOS_PER_SECOND + 1)));
+
+        // Time unit marker switching to milliseconds
+        let mut w = BitWriter::new();
+        w.write(START * NANOS_PER_SECOND, 64).unwrap();
+        w.write(0x100, 9).unwrap();
+        w.write(0b10, 2).unwrap();
+        w.write(2, 8).unwrap();
+        assert_eq!(decode_segment(&w.into_bytes()), Err(SegmentError::UnsupportedTimeUnit(2)));
+
+        // A new window of 40 leading zeros and 64 bits
+        let mut w = BitWriter::new();
+        w.write(START * NANOS_PER_SECOND, 64).unwrap();
+        w.write(0, 1).unwrap();
+        w.write(0, 64).unwrap();
+        w.write(0, 1).unwrap();
+        w.write(0b11, 2).unwrap();
+        w.write(40, 6).unwrap();
+        w.write(63, 6).unwrap();
+        w.write(0, 64).unwrap();
+        assert_eq!(decode_segment(&w.into_bytes()), Err(SegmentError::InvalidXorWindow { index: 1, leading: 40, bits: 64 }));
+    }
+}
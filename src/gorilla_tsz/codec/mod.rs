@@ -1,12 +1,44 @@
-use std::mem;
+// Values are encoded from their raw bits, never by comparing floats, so every codec hands
+// back exactly the f64 it was given: NaN payloads (like the Prometheus stale marker),
+// signalling NaNs, -0.0, subnormals and infinities included.
 
 pub mod encoder;
 pub mod decoder;
+pub mod explain;
+pub mod beringei;
+pub mod m3tsz;
+pub mod prometheus;
+pub mod reader;
+pub mod writer;
+
+#[cfg(test)]
+mod properties;
+
+use std::fmt;
 
 use super::Measurement;
 
+// The fields of a measurement, in the order they're encoded
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Field {
+    Timestamp,
+    Count,
+    Value
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Field::Timestamp => write!(f, "timestamp"),
+            Field::Count => write!(f, "count"),
+            Field::Value => write!(f, "value")
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct CodecMetadata {
-    idx: i32,
+    idx: usize,
     buf_offbits: usize,
 
     last_timestamp_delta: i64,
@@ -21,21 +53,45 @@ impl CodecMetadata {
 
     // Rounds up
     pub fn byte_len(self: &CodecMetadata) -> usize {
-        return (self.buf_offbits + 7) / 8;
+        self.buf_offbits.div_ceil(8)
     }
-}
 
-
-unsafe fn double_to_int(value: f64) -> u64
-{
-    mem::transmute::<f64, u64>(value)
+    // Number of measurements encoded or decoded so far
+    pub fn measurement_count(self: &CodecMetadata) -> usize {
+        self.idx
+    }
 }
 
-unsafe fn int_to_double(val: u64) -> f64
-{
-    mem::transmute::<u64, f64>(val)
+impl Default for CodecMetadata {
+    fn default() -> Self {
+        CodecMetadata::new()
+    }
 }
 
+// Bit patterns that break codecs, for the tests of every codec
+#[cfg(test)]
+pub(crate) const SPECIAL_VALUES: [u64; 19] = [
+    0x0000000000000000,    // 0.0
+    0x8000000000000000,    // -0.0
+    0x0000000000000001,    // smallest subnormal
+    0x000fffffffffffff,    // largest subnormal
+    0x800fffffffffffff,    // largest negative subnormal
+    0x0010000000000000,    // f64::MIN_POSITIVE
+    0x7fefffffffffffff,    // f64::MAX
+    0x7ff0000000000000,    // inf
+    0xfff0000000000000,    // -inf
+    0x7ff8000000000000,    // quiet NaN
+    0xfff8000000000000,    // negative quiet NaN
+    0x7ff0000000000001,    // signalling NaN
+    0x7ff4000000000001,    // signalling NaN with payload
+    0x7ff0000000000002,    // Prometheus stale marker
+    0x7ff8_0000_dead_beef, // quiet NaN with payload
+    0xfff8_0000_dead_beef, // negative NaN with payload
+    0xffffffffffffffff,    // all bits set
+    0x3ff0000000000000,    // 1.0
+    0xbff0000000000001     // -1.0000000000000002
+];
+
 
 #[cfg(test)]
 mod tests {
@@ -44,12 +100,11 @@ mod tests {
     use super::decoder::decode;
     use super::CodecMetadata;
 
-    fn measure_is_close(a: &Measurement, b: &Measurement) -> bool {
-        const THRESH: f64 = 0.000001;
-
+    // The codec is lossless, so values must come back bit for bit
+    fn measure_is_exact(a: &Measurement, b: &Measurement) -> bool {
         a.timestamp == b.timestamp &&
             a.count == b.count &&
-            f64::abs(a.value - b.value) < THRESH
+            a.value.to_bits() == b.value.to_bits()
     }
 
     #[test]
@@ -73,8 +128,129 @@ mod tests {
 
         for i in 0..measures.len() {
             let result = decode(&buf, &mut metadata2).unwrap();
-            assert!(measure_is_close(&result, measures.get(i).unwrap()),
+            assert!(measure_is_exact(&result, measures.get(i).unwrap()),
                     "wanted: {:?}, got: {:?}", measures.get(i).unwrap(), result);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_nan_payloads_codec()
+    {
+        const STALE_NAN: u64 = 0x7ff0000000000002;
+
+        let mut buf = [0u8; 512];
+        let mut metadata = CodecMetadata::new();
+
+        // -1.0000000000000002 to a stale marker flips both the top and bottom bit, which
+        // needs all 64 significant bits
+        let values = [1.0f64.to_bits(), STALE_NAN, 0xbff0000000000001, STALE_NAN,
+                      f64::NAN.to_bits(), 0x7ff8_0000_dead_beef, STALE_NAN, 2.0f64.to_bits()];
+
+        for (i, bits) in values.iter().enumerate() {
+            let measure = Measurement{timestamp: 1567029708 + i as u64 * 15, count: 1, value: f64::from_bits(*bits)};
+            assert_eq!(encode(&mut buf, &mut metadata, &measure).ok(), Some(()));
+        }
+
+        let mut metadata2 = CodecMetadata::new();
+
+        for bits in values.iter() {
+            let result = decode(&buf, &mut metadata2).unwrap();
+            assert_eq!(result.value.to_bits(), *bits, "wanted: {:x}, got: {:x}", bits, result.value.to_bits());
+        }
+    }
+
+    #[test]
+    fn test_encoder_buffer_full()
+    {
+        use super::Field;
+        use super::encoder::EncoderErrorKind;
+
+        let mut buf = [0u8; 12];
+        let mut metadata = CodecMetadata::new();
+
+        encode(&mut buf, &mut metadata, &Measurement{timestamp: 100, count: 1, value: 1.0}).unwrap();
+        let error = encode(&mut buf, &mut metadata, &Measurement{timestamp: 110, count: 1, value: 2.5}).unwrap_err();
+
+        // 80 bits for the first measurement, then 8 for the delta and 1 for the count
+        assert!(error.is_buffer_full());
+        assert_eq!((error.index, error.offset, error.field), (1, 89, Field::Value));
+        assert!(matches!(error.kind, EncoderErrorKind::BitCopy(_)));
+    }
+
+    // Every ordered pair of awkward bit patterns, through every codec
+    #[test]
+    fn test_float_bit_matrix()
+    {
+        use super::{beringei, m3tsz, prometheus, SPECIAL_VALUES};
+        use super::super::block::Block;
+
+        let mut measures = Vec::new();
+        for a in SPECIAL_VALUES.iter() {
+            for b in SPECIAL_VALUES.iter() {
+                for bits in [a, b] {
+                    let timestamp = 1567029708 + measures.len() as u64 * 15;
+                    measures.push(Measurement{timestamp, count: 1, value: f64::from_bits(*bits)});
+                }
+            }
+        }
+
+        let check = |codec: &str, decoded: &[Measurement]| {
+            assert_eq!(decoded.len(), measures.len(), "{}", codec);
+            for (i, (a, b)) in measures.iter().zip(decoded).enumerate() {
+                assert!(measure_is_exact(a, b), "{} measurement {}: wanted {:#018x}, got {:#018x}",
+                        codec, i, a.value.to_bits(), b.value.to_bits());
+            }
+        };
+
+        let mut buf = vec![0u8; measures.len() * 16];
+        let mut metadata = CodecMetadata::new();
+        for m in measures.iter() {
+            encode(&mut buf, &mut metadata, m).unwrap();
+        }
+        let mut metadata2 = CodecMetadata::new();
+        let decoded: Vec<Measurement> = measures.iter().map(|_| decode(&buf, &mut metadata2).unwrap()).collect();
+        check("codec", &decoded);
+
+        let block = Block::from_bytes(&Block::encode(&measures).unwrap().to_bytes()).unwrap();
+        check("block", &block.measurements().collect::<Result<Vec<_>, _>>().unwrap());
+
+        let chunk = prometheus::encode_chunk(&measures).unwrap();
+        check("prometheus", &prometheus::decode_chunk(&chunk).unwrap());
+
+        let stream = beringei::encode_stream(&measures).unwrap();
+        check("beringei", &beringei::decode_stream(&stream, measures.len()).unwrap());
+
+        let segment = m3tsz::encode_segment(1567029600, &measures).unwrap();
+        check("m3tsz", &m3tsz::decode_segment(&segment).unwrap());
+    }
+
+    // Deterministic stand in for the fuzz targets: no decoder may panic on any input
+    #[test]
+    fn test_arbitrary_input()
+    {
+        use super::{beringei, m3tsz, prometheus, decoder, explain, reader};
+        use super::super::block::Block;
+
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..5000 {
+            let len = (next() % 96) as usize;
+            let buf: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            let count = (next() % 64) as usize;
+
+            for _ in decoder::measurements(&buf, count) {}
+            for _ in reader::StreamDecoder::new(&buf[..], count) {}
+            explain::explain(&buf, count);
+            let _ = prometheus::decode_chunk(&buf);
+            let _ = beringei::decode_stream(&buf, count);
+            let _ = m3tsz::decode_segment(&buf);
+            let _ = Block::from_bytes(&buf).map(|b| b.measurements().count());
+        }
+    }
+}
@@ -0,0 +1,356 @@
+// Prometheus TSDB XOR chunks (tsdb/chunkenc/xor.go). These are a close relative of our own
+// encoding: millisecond int64 timestamps with bucketed delta-of-deltas, the same XOR value
+// scheme with a 5 bit leading zero count, and a 2 byte sample count header. Prometheus has
+// no per-sample count, so decoded measurements get a count of 1 and encoding ignores it.
+
+use std::error;
+use std::fmt;
+
+use super::Measurement;
+use super::super::utils::bitcopy::{BitCopyError, BitValue};
+use super::super::utils::bitstream::{BitReader, BitWriter};
+use super::super::utils::varint;
+use super::super::utils::varint::VarIntError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChunkError {
+    BitCopy(BitCopyError),
+    VarInt(VarIntError),
+    UnterminatedVarint,
+    TooManySamples(usize),
+    // Prometheus timestamps are int64
+    TimestampOutOfRange { index: usize, timestamp: u64 },
+    NegativeTimestamp { index: usize, timestamp: i64 },
+    InvalidXorWindow { leading: u32, sig_bits: u32 },
+    MissingXorWindow
+}
+
+impl ChunkError {
+    // The chunk ended part way through, as opposed to holding bits Prometheus doesn't write
+    pub fn is_truncated(&self) -> bool {
+        matches!(self, ChunkError::BitCopy(BitCopyError::SourceOverrun { .. }) | ChunkError::VarInt(VarIntError::Truncated(_)))
+    }
+}
+
+impl From<BitCopyError> for ChunkError {
+    fn from(e: BitCopyError) -> Self {
+        ChunkError::BitCopy(e)
+    }
+}
+
+impl From<VarIntError> for ChunkError {
+    fn from(e: VarIntError) -> Self {
+        ChunkError::VarInt(e)
+    }
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkError::BitCopy(e) => write!(f, "{}", e),
+            ChunkError::VarInt(e) => write!(f, "{}", e),
+            ChunkError::UnterminatedVarint => write!(f, "could not find end of varint"),
+            ChunkError::TooManySamples(n) => write!(f, "too many samples for one chunk: {}", n),
+            ChunkError::TimestampOutOfRange { index, timestamp } => write!(f, "timestamp out of range in sample {}: {}", index, timestamp),
+            ChunkError::NegativeTimestamp { index, timestamp } => write!(f, "negative timestamp in sample {}: {}", index, timestamp),
+            ChunkError::InvalidXorWindow { leading, sig_bits } =>
+                write!(f, "invalid xor window: {} leading, {} significant bits", leading, sig_bits),
+            ChunkError::MissingXorWindow => write!(f, "no previous xor window")
+        }
+    }
+}
+
+impl error::Error for ChunkError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ChunkError::BitCopy(e) => Some(e),
+            ChunkError::VarInt(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+const HEADER_BYTES: usize = 2;
+
+// Delta-of-delta buckets, as (control bits, control length, value bits)
+const DOD_BUCKETS: [(u64, usize, usize); 3] = [(0b10, 2, 14), (0b110, 3, 17), (0b1110, 4, 20)];
+
+fn write_varint(writer: &mut BitWriter, value: u64) -> Result<(), ChunkError>
+{
+    let mut varint_buf = [0u8; 10];
+    let sz = varint::encode(value, &mut varint_buf)?;
+
+    for b in &varint_buf[..sz] {
+        writer.write(*b as u64, 8)?;
+    }
+    Ok(())
+}
+
+fn read_varint(reader: &mut BitReader) -> Result<u64, ChunkError>
+{
+    let mut bytes = [0u8; 10];
+
+    for i in 0..bytes.len() {
+        bytes[i] = reader.read(8)? as u8;
+
+        if bytes[i] < 128 {
+            return Ok(varint::decode(&bytes[..=i])?.0);
+        }
+    }
+
+    Err(ChunkError::UnterminatedVarint)
+}
+
+// Leading and trailing zeros of the current XOR window
+type XorWindow = Option<(u32, u32)>;
+
+fn write_value(writer: &mut BitWriter, window: &mut XorWindow, prev: f64, value: f64) -> Result<(), ChunkError>
+{
+    let xor = prev.to_bits() ^ value.to_bits();
+
+    if xor == 0 {
+        return Ok(writer.write_bit(BitValue::Zero)?);
+    }
+
+    writer.write_bit(BitValue::One)?;
+
+    // Prometheus clamps leading zeros to fit in 5 bits
+    let leading = u32::min(xor.leading_zeros(), 31);
+    let trailing = xor.trailing_zeros();
+
+    if let Some((prev_leading, prev_trailing)) = *window {
+        if leading >= prev_leading && trailing >= prev_trailing {
+            writer.write_bit(BitValue::Zero)?;
+            return Ok(writer.write(xor >> prev_trailing, (64 - prev_leading - prev_trailing) as usize)?);
+        }
+    }
+
+    let sig_bits = 64 - leading - trailing;
+
+    writer.write_bit(BitValue::One)?;
+    writer.write(leading as u64, 5)?;
+    // 64 significant bits are written as 0
+    writer.write((sig_bits % 64) as u64, 6)?;
+    writer.write(xor >> trailing, sig_bits as usize)?;
+
+    *window = Some((leading, trailing));
+    Ok(())
+}
+
+fn read_value(reader: &mut BitReader, window: &mut XorWindow, prev: f64) -> Result<f64, ChunkError>
+{
+    if reader.read_bit()? == BitValue::Zero {
+        return Ok(prev);
+    }
+
+    if reader.read_bit()? == BitValue::One {
+        let leading = reader.read(5)? as u32;
+        let sig_bits = match reader.read(6)? as u32 {
+            0 => 64,
+            n => n
+        };
+
+        let trailing = 64u32.checked_sub(leading + sig_bits)
+            .ok_or(ChunkError::InvalidXorWindow { leading, sig_bits })?;
+
+        *window = Some((leading, trailing));
+    }
+
+    let (leading, trailing) = window.ok_or(ChunkError::MissingXorWindow)?;
+    let xor = reader.read((64 - leading - trailing) as usize)? << trailing;
+
+    Ok(f64::from_bits(prev.to_bits() ^ xor))
+}
+
+// Encodes measurements into the data of a single XOR chunk
+pub fn encode_chunk(measurements: &[Measurement]) -> Result<Vec<u8>, ChunkError>
+{
+    if measurements.len() > u16::MAX as usize {
+        return Err(ChunkError::TooManySamples(measurements.len()));
+    }
+
+    let mut writer = BitWriter::new();
+    let mut window = None;
+
+    writer.write(measurements.len() as u64, HEADER_BYTES * 8)?;
+
+    let mut prev_timestamp = 0i64;
+    let mut prev_delta = 0u64;
+    let mut prev_value = 0.0;
+
+    for (idx, measurement) in measurements.iter().enumerate() {
+        if measurement.timestamp > i64::MAX as u64 {
+            return Err(ChunkError::TimestampOutOfRange { index: idx, timestamp: measurement.timestamp });
+        }
+
+        let timestamp = measurement.timestamp as i64;
+        let delta = timestamp.wrapping_sub(prev_timestamp) as u64;
+
+        match idx {
+            0 => {
+                write_varint(&mut writer, varint::encode_zigzag(timestamp))?;
+                writer.write(measurement.value.to_bits(), 64)?;
+            },
+            1 => {
+                write_varint(&mut writer, delta)?;
+                write_value(&mut writer, &mut window, prev_value, measurement.value)?;
+            },
+            _ => {
+                let dod = delta.wrapping_sub(prev_delta) as i64;
+
+                if dod == 0 {
+                    writer.write_bit(BitValue::Zero)?;
+                } else {
+                    // Buckets hold [-(2^(n-1) - 1), 2^(n-1)]
+                    let bucket = DOD_BUCKETS.iter().find(|&&(_, _, nbits)| {
+                        -((1i64 << (nbits - 1)) - 1) <= dod && dod <= 1i64 << (nbits - 1)
+                    });
+
+                    match bucket {
+                        Some(&(control, control_bits, nbits)) => {
+                            writer.write(control, control_bits)?;
+                            writer.write(dod as u64 & ((1 << nbits) - 1), nbits)?;
+                        },
+                        None => {
+                            writer.write(0b1111, 4)?;
+                            writer.write(dod as u64, 64)?;
+                        }
+                    }
+                }
+
+                write_value(&mut writer, &mut window, prev_value, measurement.value)?;
+            }
+        }
+
+        prev_timestamp = timestamp;
+        prev_delta = delta;
+        prev_value = measurement.value;
+    }
+
+    Ok(writer.into_bytes())
+}
+
+// Decodes all samples of a single XOR chunk
+pub fn decode_chunk(chunk: &[u8]) -> Result<Vec<Measurement>, ChunkError>
+{
+    let mut reader = BitReader::new(chunk);
+    let mut window = None;
+
+    let num_samples = reader.read(HEADER_BYTES * 8)? as usize;
+    let mut measurements = Vec::with_capacity(num_samples);
+
+    let mut timestamp = 0i64;
+    let mut delta = 0u64;
+    let mut value = 0.0;
+
+    for idx in 0..num_samples {
+        match idx {
+            0 => {
+                timestamp = varint::decode_zigzag(read_varint(&mut reader)?);
+                value = f64::from_bits(reader.read(64)?);
+            },
+            1 => {
+                delta = read_varint(&mut reader)?;
+                timestamp = timestamp.wrapping_add(delta as i64);
+                value = read_value(&mut reader, &mut window, value)?;
+            },
+            _ => {
+                let mut control = 0;
+                for _ in 0..4 {
+                    if reader.read_bit()? == BitValue::Zero {
+                        break;
+                    }
+                    control += 1;
+                }
+
+                let dod = match control {
+                    0 => 0,
+                    4 => reader.read(64)? as i64,
+                    n => {
+                        let nbits = DOD_BUCKETS[n - 1].2;
+                        let bits = reader.read(nbits)? as i64;
+
+                        // Sign extend
+                        if bits > 1 << (nbits - 1) { bits - (1 << nbits) } else { bits }
+                    }
+                };
+
+                delta = delta.wrapping_add(dod as u64);
+                timestamp = timestamp.wrapping_add(delta as i64);
+                value = read_value(&mut reader, &mut window, value)?;
+            }
+        }
+
+        if timestamp < 0 {
+            return Err(ChunkError::NegativeTimestamp { index: idx, timestamp });
+        }
+
+        measurements.push(Measurement { timestamp: timestamp as u64, count: 1, value });
+    }
+
+    Ok(measurements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Worked out by hand from the Prometheus format:
+    //   00 04                    4 samples
+    //   d0 0f                    varint(1000)
+    //   3f f0 00 00 00 00 00 00  1.0
+    //   e8 07                    uvarint(1000), then value bit 0 (unchanged)
+    //   ...                      dod 0; value 2.0: 1 1 00001 001011 11111111111
+    //                            dod -500: 10 11111000001100; value bit 0
+    const FIXTURE: [u8; 20] = [0x00, 0x04, 0xd0, 0x0f, 0x3f, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                               0xe8, 0x07, 0x30, 0x97, 0xff, 0xef, 0x83, 0x00];
+
+    fn fixture_measurements() -> Vec<Measurement> {
+        vec![Measurement{timestamp: 1000, count: 1, value: 1.0},
+             Measurement{timestamp: 2000, count: 1, value: 1.0},
+             Measurement{timestamp: 3000, count: 1, value: 2.0},
+             Measurement{timestamp: 3500, count: 1, value: 2.0}]
+    }
+
+    #[test]
+    fn test_fixture()
+    {
+        assert_eq!(decode_chunk(&FIXTURE).unwrap(), fixture_measurements());
+        assert_eq!(encode_chunk(&fixture_measurements()).unwrap(), FIXTURE.to_vec());
+    }
+
+    #[test]
+    fn test_roundtrip()
+    {
+        let mut measures = Vec::new();
+        let mut timestamp = 1567029708000u64;
+
+        // Exercise every dod bucket, including the 64 bit escape, and negative deltas
+        for (i, jitter) in [0i64, 1, -1, 8192, -8191, 65536, -65535, 524288, -524287, 1 << 40, -(1 << 40), -30000].iter().enumerate() {
+            timestamp = (timestamp as i64 + 15000 + jitter) as u64;
+            measures.push(Measurement{timestamp, count: 1, value: 43.568 + (i as f64 * 0.0023456)});
+        }
+
+        measures.push(Measurement{timestamp: timestamp + 1, count: 1, value: -1.0000000000000002});
+        measures.push(Measurement{timestamp: timestamp + 2, count: 1, value: f64::from_bits(0x7ff0000000000002)});
+
+        let chunk = encode_chunk(&measures).unwrap();
+        let decoded = decode_chunk(&chunk).unwrap();
+
+        assert_eq!(decoded.len(), measures.len());
+        for (a, b) in measures.iter().zip(decoded.iter()) {
+            assert_eq!(a.timestamp, b.timestamp);
+            assert_eq!(a.value.to_bits(), b.value.to_bits());
+        }
+    }
+
+    #[test]
+    fn test_invalid_chunks()
+    {
+        assert_eq!(encode_chunk(&[Measurement{timestamp: u64::MAX, count: 1, value: 0.0}]),
+                   Err(ChunkError::TimestampOutOfRange { index: 0, timestamp: u64::MAX }));
+        assert!(decode_chunk(&FIXTURE[..FIXTURE.len() - 4]).unwrap_err().is_truncated());
+        assert!(decode_chunk(&[0x00]).unwrap_err().is_truncated());
+        assert_eq!(decode_chunk(&[0x00, 0x00]).unwrap(), vec![]);
+    }
+}
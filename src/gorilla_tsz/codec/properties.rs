@@ -0,0 +1,226 @@
+// Property based round-trip tests for every encoder/decoder pair. Generated series lean
+// towards the values that break codecs: extreme and wrapping timestamps and counts, huge
+// deltas, NaN payloads, signed zeros, subnormals and infinities. Round-trips must be bit exact.
+
+use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+use super::Measurement;
+use super::{beringei, decoder, encoder, m3tsz, prometheus, CodecMetadata, SPECIAL_VALUES};
+use super::reader::{StreamDecoder, Trickle};
+use super::writer::StreamEncoder;
+use super::super::block::{Block, BlockEncoder};
+
+const SPECIAL_U64S: [u64; 8] = [0, 1, i64::MAX as u64, i64::MAX as u64 + 1, u64::MAX - 1, u64::MAX, 1 << 32, 1 << 31];
+
+#[derive(Clone, Debug)]
+struct Series(Vec<Measurement>);
+
+fn arbitrary_u64(g: &mut Gen, prev: Option<u64>) -> u64
+{
+    match (u8::arbitrary(g) % 4, prev) {
+        (0, _) => *g.choose(&SPECIAL_U64S).unwrap(),
+        (1, _) | (_, None) => u64::arbitrary(g),
+        // Small steps, possibly backwards or wrapping
+        (_, Some(prev)) => prev.wrapping_add(i16::arbitrary(g) as u64)
+    }
+}
+
+fn arbitrary_value(g: &mut Gen, prev: Option<f64>) -> f64
+{
+    match (u8::arbitrary(g) % 4, prev) {
+        (0, _) => f64::from_bits(*g.choose(&SPECIAL_VALUES).unwrap()),
+        (1, _) | (_, None) => f64::from_bits(u64::arbitrary(g)),
+        // Repeats and near repeats exercise the xor zero and window reuse paths
+        (2, Some(prev)) => prev,
+        (_, Some(prev)) => f64::from_bits(prev.to_bits() ^ (u8::arbitrary(g) as u64) << (u8::arbitrary(g) % 56))
+    }
+}
+
+impl Arbitrary for Series {
+    fn arbitrary(g: &mut Gen) -> Series {
+        let len = usize::arbitrary(g) % (g.size() * 2);
+        let mut measurements: Vec<Measurement> = Vec::with_capacity(len);
+
+        for _ in 0..len {
+            let prev = measurements.last();
+            measurements.push(Measurement {
+                timestamp: arbitrary_u64(g, prev.map(|m| m.timestamp)),
+                count: arbitrary_u64(g, prev.map(|m| m.count)),
+                value: arbitrary_value(g, prev.map(|m| m.value))
+            });
+        }
+
+        Series(measurements)
+    }
+
+    // Either half, then the series with each measurement dropped in turn
+    fn shrink(&self) -> Box<dyn Iterator<Item = Series>> {
+        let series = self.0.clone();
+        let half = series.len() / 2;
+
+        let halves = if series.is_empty() { vec![] } else { vec![series[..half].to_vec(), series[half..].to_vec()] };
+        let dropped = (0..series.len()).map(move |i| {
+            let mut smaller = series.clone();
+            smaller.remove(i);
+            smaller
+        });
+
+        Box::new(halves.into_iter().chain(dropped).map(Series))
+    }
+}
+
+fn same_bits(a: &[Measurement], b: &[Measurement]) -> bool
+{
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| {
+        a.timestamp == b.timestamp && a.count == b.count && a.value.to_bits() == b.value.to_bits()
+    })
+}
+
+fn quickcheck(property: fn(Series) -> bool)
+{
+    QuickCheck::new().tests(500).quickcheck(property);
+}
+
+#[test]
+fn prop_codec_roundtrip()
+{
+    fn property(series: Series) -> bool {
+        let mut buf = vec![0u8; series.0.len() * 48];
+        let mut metadata = CodecMetadata::new();
+
+        for m in &series.0 {
+            if encoder::encode(&mut buf, &mut metadata, m).is_err() {
+                return false;
+            }
+        }
+
+        let decoded: Result<Vec<_>, _> = decoder::measurements(&buf[..metadata.byte_len()], series.0.len()).collect();
+        decoded.is_ok_and(|d| same_bits(&series.0, &d))
+    }
+
+    quickcheck(property);
+}
+
+#[test]
+fn prop_block_roundtrip()
+{
+    fn property(series: Series) -> bool {
+        let mut encoder = BlockEncoder::new();
+        for m in &series.0 {
+            if encoder.push(m).is_err() {
+                return false;
+            }
+        }
+
+        let block = Block::from_bytes(&encoder.finish().to_bytes()).unwrap();
+        let decoded: Result<Vec<_>, _> = block.measurements().collect();
+        decoded.is_ok_and(|d| same_bits(&series.0, &d))
+    }
+
+    quickcheck(property);
+}
+
+#[test]
+fn prop_stream_encoder_matches_block()
+{
+    fn property(series: Series) -> bool {
+        let mut encoder = StreamEncoder::new(Vec::new());
+        for m in &series.0 {
+            if encoder.push(m).is_err() {
+                return false;
+            }
+        }
+
+        encoder.finish().ok() == Block::encode(&series.0).ok().map(|block| block.data)
+    }
+
+    quickcheck(property);
+}
+
+#[test]
+fn prop_stream_decoder_roundtrip()
+{
+    // Vary the read size with the series so measurements straddle reads at every offset
+    fn property(series: Series) -> bool {
+        let bytes = match Block::encode(&series.0) {
+            Ok(block) => block.to_bytes(),
+            Err(_) => return false
+        };
+
+        let reader = Trickle { data: &bytes, step: 1 + series.0.len() % 7 };
+        let decoded: Result<Vec<Measurement>, _> = match StreamDecoder::from_block(reader) {
+            Ok(decoder) => decoder.collect(),
+            Err(_) => return false
+        };
+
+        decoded.is_ok_and(|d| same_bits(&series.0, &d))
+    }
+
+    quickcheck(property);
+}
+
+#[test]
+fn prop_prometheus_roundtrip()
+{
+    // Prometheus timestamps are int64 and samples have no count
+    fn property(series: Series) -> bool {
+        let series: Vec<Measurement> = series.0.iter()
+            .map(|m| Measurement { timestamp: m.timestamp & i64::MAX as u64, count: 1, value: m.value })
+            .collect();
+
+        let decoded = prometheus::encode_chunk(&series).and_then(|chunk| prometheus::decode_chunk(&chunk));
+        decoded.is_ok_and(|d| same_bits(&series, &d))
+    }
+
+    quickcheck(property);
+}
+
+#[test]
+fn prop_beringei_roundtrip()
+{
+    // Beringei needs increasing 32 bit timestamps, with the first in 31 bits, and has no count
+    fn property(series: Series) -> bool {
+        let mut timestamps: Vec<u64> = series.0.iter().map(|m| m.timestamp % (1 << 31)).collect();
+        timestamps.sort_unstable();
+
+        let series: Vec<Measurement> = series.0.iter().zip(timestamps)
+            .map(|(m, timestamp)| Measurement { timestamp, count: 1, value: m.value })
+            .collect();
+
+        let decoded = beringei::encode_stream(&series).and_then(|stream| beringei::decode_stream(&stream, series.len()));
+        decoded.is_ok_and(|d| same_bits(&series, &d))
+    }
+
+    quickcheck(property);
+}
+
+#[test]
+fn prop_m3tsz_roundtrip()
+{
+    // M3TSZ delta-of-deltas are at most 32 bits, and there is no count
+    fn property(series: Series) -> bool {
+        let series: Vec<Measurement> = series.0.iter()
+            .map(|m| Measurement { timestamp: m.timestamp % (1 << 30), count: 1, value: m.value })
+            .collect();
+        let start = series.first().map_or(0, |m| m.timestamp - m.timestamp % 7200);
+
+        let decoded = m3tsz::encode_segment(start, &series).and_then(|segment| m3tsz::decode_segment(&segment));
+        decoded.is_ok_and(|d| same_bits(&series, &d))
+    }
+
+    quickcheck(property);
+}
+
+#[test]
+fn test_wraparound()
+{
+    let series = [Measurement { timestamp: u64::MAX, count: u64::MAX, value: -0.0 },
+                  Measurement { timestamp: 0, count: 0, value: f64::from_bits(1) },
+                  Measurement { timestamp: u64::MAX, count: 1, value: f64::NEG_INFINITY },
+                  Measurement { timestamp: i64::MAX as u64 + 1, count: u64::MAX, value: f64::from_bits(0x7ff4000000000001) },
+                  Measurement { timestamp: 1, count: i64::MAX as u64, value: f64::from_bits(0x7ff4000000000001) }];
+
+    let block = Block::encode(&series).unwrap();
+    let decoded: Vec<Measurement> = block.measurements().map(Result::unwrap).collect();
+    assert!(same_bits(&series, &decoded), "{:?}", decoded);
+}
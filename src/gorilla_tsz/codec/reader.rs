@@ -0,0 +1,292 @@
+// Decodes straight from an io::Read, for blocks in files or coming off a socket. Input is
+// read in chunks into a window that holds the measurement being decoded. When a measurement
+// runs off the end of the window, it is decoded again once more input has been read. If
+// the input has ended by then, the error is Truncated, which is kept apart from Corrupt.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::Read;
+
+use super::CodecMetadata;
+use super::Measurement;
+use super::decoder;
+use super::decoder::{DecoderError, DecoderErrorKind};
+use super::super::block::{BlockError, MAGIC, VERSION};
+use super::super::utils::bitcopy::BitCopyError;
+use super::super::utils::varint;
+
+const READ_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    Block(BlockError),
+    // The input ended part way through a measurement
+    Truncated(DecoderError),
+    Corrupt(DecoderError)
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "{}", e),
+            ReadError::Block(e) => write!(f, "invalid block: {}", e),
+            ReadError::Truncated(e) => write!(f, "input truncated: {}", e),
+            ReadError::Corrupt(e) => write!(f, "decoding failed: {}", e)
+        }
+    }
+}
+
+impl error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ReadError::Io(e) => Some(e),
+            ReadError::Block(e) => Some(e),
+            ReadError::Truncated(e) | ReadError::Corrupt(e) => Some(e)
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<BlockError> for ReadError {
+    fn from(e: BlockError) -> Self {
+        ReadError::Block(e)
+    }
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> Result<u8, ReadError>
+{
+    let mut byte = [0u8; 1];
+
+    match reader.read_exact(&mut byte) {
+        Ok(()) => Ok(byte[0]),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(ReadError::Block(BlockError::Truncated)),
+        Err(e) => Err(ReadError::Io(e))
+    }
+}
+
+// Reads a block header, leaving the reader at the start of the encoded measurements
+pub fn read_block_header<R: Read>(reader: &mut R) -> Result<usize, ReadError>
+{
+    let mut magic = [0u8; 4];
+    for b in magic.iter_mut() {
+        *b = read_byte(reader)?;
+    }
+
+    if &magic != MAGIC {
+        return Err(ReadError::Block(BlockError::InvalidMagic));
+    }
+
+    let version = read_byte(reader)?;
+    if version != VERSION {
+        return Err(ReadError::Block(BlockError::UnsupportedVersion(version)));
+    }
+
+    let mut varint_buf = [0u8; 10];
+    for i in 0..varint_buf.len() {
+        varint_buf[i] = read_byte(reader)?;
+
+        if varint_buf[i] < 128 {
+            let (count, _) = varint::decode(&varint_buf[..=i]).map_err(|_| BlockError::Truncated)?;
+            return Ok(count as usize);
+        }
+    }
+
+    Err(ReadError::Block(BlockError::InvalidCount))
+}
+
+// Moves an error from the window to where it is in the whole stream
+fn rebase(mut e: DecoderError, bits: usize) -> DecoderError
+{
+    e.offset += bits;
+
+    if let DecoderErrorKind::BitCopy(BitCopyError::SourceOverrun { offset, len, .. }) = &mut e.kind {
+        *offset += bits;
+        *len += bits;
+    }
+    e
+}
+
+pub struct StreamDecoder<R: Read> {
+    reader: R,
+    // Input not yet decoded, starting with the byte the next measurement starts in
+    buf: Vec<u8>,
+    // Offsets in here are relative to `buf`, not the whole stream
+    metadata: CodecMetadata,
+    consumed: usize,
+    remaining: usize,
+    eof: bool
+}
+
+impl<R: Read> StreamDecoder<R> {
+    // Decodes `count` measurements, like decoder::measurements()
+    pub fn new(reader: R, count: usize) -> StreamDecoder<R> {
+        StreamDecoder { reader, buf: Vec::new(), metadata: CodecMetadata::new(), consumed: 0, remaining: count, eof: false }
+    }
+
+    // Decodes a whole block file, header included
+    pub fn from_block(mut reader: R) -> Result<StreamDecoder<R>, ReadError> {
+        let count = read_block_header(&mut reader)?;
+        Ok(StreamDecoder::new(reader, count))
+    }
+
+    // Measurements left to decode
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    // Drops the bytes already decoded and appends the next chunk of input
+    fn refill(&mut self) -> io::Result<()> {
+        let whole = self.metadata.buf_offbits / 8;
+        self.buf.drain(..whole);
+        self.metadata.buf_offbits -= whole * 8;
+        self.consumed += whole;
+
+        let len = self.buf.len();
+        self.buf.resize(len + READ_SIZE, 0);
+
+        loop {
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(n) => {
+                    self.buf.truncate(len + n);
+                    self.eof = n == 0;
+                    return Ok(());
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn decode_next(&mut self) -> Result<Measurement, ReadError> {
+        loop {
+            let mut metadata = self.metadata.clone();
+
+            match decoder::decode(&self.buf, &mut metadata) {
+                Ok(measurement) => {
+                    self.metadata = metadata;
+                    return Ok(measurement);
+                },
+                Err(e) if e.is_truncated() && !self.eof => self.refill()?,
+                Err(e) => {
+                    let e = rebase(e, self.consumed * 8);
+                    return Err(if e.is_truncated() { ReadError::Truncated(e) } else { ReadError::Corrupt(e) });
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for StreamDecoder<R> {
+    type Item = Result<Measurement, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let result = self.decode_next();
+
+        // Nothing after an error can be trusted, so stop there
+        self.remaining = match result {
+            Ok(_) => self.remaining - 1,
+            Err(_) => 0
+        };
+
+        Some(result)
+    }
+}
+
+// Hands out input a few bytes at a time, like a slow socket
+#[cfg(test)]
+pub(crate) struct Trickle<'a> {
+    pub data: &'a [u8],
+    pub step: usize
+}
+
+#[cfg(test)]
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.step.min(buf.len()).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::block::Block;
+    use super::super::decoder::Field;
+
+    fn measures() -> Vec<Measurement> {
+        (0..3000u64)
+            .map(|i| Measurement{timestamp: 1567029708 + i * 10 + i % 7, count: 1 + i % 3, value: (i as f64).sin()})
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_decoder()
+    {
+        let measures = measures();
+        let bytes = Block::encode(&measures).unwrap().to_bytes();
+
+        for step in [1, 3, 5000] {
+            let decoder = StreamDecoder::from_block(Trickle { data: &bytes, step }).unwrap();
+            assert_eq!(decoder.remaining(), measures.len());
+
+            let decoded: Vec<Measurement> = decoder.map(Result::unwrap).collect();
+            assert_eq!(decoded, measures);
+        }
+
+        assert!(matches!(StreamDecoder::from_block(&b"GTSZ\x02\x00"[..]), Err(ReadError::Block(BlockError::UnsupportedVersion(2)))));
+        assert!(matches!(StreamDecoder::from_block(&b"GTS"[..]), Err(ReadError::Block(BlockError::Truncated))));
+        assert_eq!(StreamDecoder::new(&[][..], 0).count(), 0);
+    }
+
+    #[test]
+    fn test_stream_decoder_errors()
+    {
+        let measures = measures();
+        let block = Block::encode(&measures).unwrap();
+
+        // Input ends within the last measurement
+        let short = &block.data[..block.data.len() - 1];
+        let results: Vec<_> = StreamDecoder::new(Trickle { data: short, step: 7 }, measures.len()).collect();
+
+        assert_eq!(results.len(), measures.len());
+        match results.last().unwrap() {
+            Err(ReadError::Truncated(e)) => {
+                let expected = decoder::measurements(short, measures.len()).find_map(Result::err).unwrap();
+                assert_eq!(*e, expected);
+                assert_eq!(e.index, measures.len() - 1);
+            },
+            r => panic!("expected truncation, got {:?}", r)
+        }
+
+        // A delta of 10, a repeated count, then a new xor window with 31 leading zeros and
+        // 64 significant bits, well within the input
+        let mut corrupt = Block::encode(&[Measurement{timestamp: 100, count: 1, value: 0.0}]).unwrap().data;
+        corrupt.extend_from_slice(&[0x14, 0b0110_1111, 0b1000_0000]);
+        corrupt.extend_from_slice(&[0u8; 32]);
+
+        let results: Vec<_> = StreamDecoder::new(&corrupt[..], 2).collect();
+        match &results[1] {
+            Err(ReadError::Corrupt(e)) => {
+                assert_eq!((e.index, e.offset, e.field), (1, 89, Field::Value));
+                assert_eq!(e.kind, DecoderErrorKind::InvalidXorWindow { leading: 31, sig_bits: 64 });
+            },
+            r => panic!("expected corruption, got {:?}", r)
+        }
+    }
+}
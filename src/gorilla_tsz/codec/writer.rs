@@ -0,0 +1,159 @@
+// Encodes straight to an io::Write, for series too long to hold as a block in memory. Only
+// the byte being filled is kept back: each measurement is encoded into a small scratch
+// buffer behind it and every whole byte is written out. The bits are the same as `encode`
+// into one big slice.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+use super::CodecMetadata;
+use super::Measurement;
+use super::encoder;
+use super::encoder::{EncoderError, MAX_MEASUREMENT_BYTES};
+
+#[derive(Debug)]
+pub enum WriteError {
+    Io(io::Error),
+    Encoder(EncoderError)
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::Io(e) => write!(f, "{}", e),
+            WriteError::Encoder(e) => write!(f, "encoding failed: {}", e)
+        }
+    }
+}
+
+impl error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            WriteError::Io(e) => Some(e),
+            WriteError::Encoder(e) => Some(e)
+        }
+    }
+}
+
+impl From<io::Error> for WriteError {
+    fn from(e: io::Error) -> Self {
+        WriteError::Io(e)
+    }
+}
+
+impl From<EncoderError> for WriteError {
+    fn from(e: EncoderError) -> Self {
+        WriteError::Encoder(e)
+    }
+}
+
+// After an error the stream is incomplete, so the encoder shouldn't be used again
+pub struct StreamEncoder<W: Write> {
+    writer: W,
+    // The partially filled byte, followed by room for one more measurement
+    buf: [u8; MAX_MEASUREMENT_BYTES + 1],
+    // Offsets in here are relative to `buf`, not the whole stream
+    metadata: CodecMetadata,
+    written: usize
+}
+
+impl<W: Write> StreamEncoder<W> {
+    pub fn new(writer: W) -> StreamEncoder<W> {
+        StreamEncoder { writer, buf: [0u8; MAX_MEASUREMENT_BYTES + 1], metadata: CodecMetadata::new(), written: 0 }
+    }
+
+    pub fn push(&mut self, measurement: &Measurement) -> Result<(), WriteError> {
+        let written = self.written;
+
+        encoder::encode(&mut self.buf, &mut self.metadata, measurement).map_err(|mut e| {
+            e.offset += written * 8;
+            e
+        })?;
+
+        let whole = self.metadata.buf_offbits / 8;
+        self.writer.write_all(&self.buf[..whole])?;
+
+        // Carry the partial byte over to the front, with zeros behind it for padding
+        self.buf[0] = self.buf[whole];
+        self.buf[1..].fill(0);
+
+        self.metadata.buf_offbits -= whole * 8;
+        self.written += whole;
+        Ok(())
+    }
+
+    pub fn measurement_count(&self) -> usize {
+        self.metadata.measurement_count()
+    }
+
+    // Length of the stream once it's finished, including the partial byte
+    pub fn byte_len(&self) -> usize {
+        self.written + self.metadata.byte_len()
+    }
+
+    // Writes out the last byte, zero padded, and flushes the writer
+    pub fn finish(mut self) -> Result<W, WriteError> {
+        if self.metadata.buf_offbits > 0 {
+            self.writer.write_all(&self.buf[..1])?;
+        }
+
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::block::Block;
+
+    #[test]
+    fn test_stream_encoder()
+    {
+        let measures: Vec<Measurement> = (0..500u64)
+            .map(|i| Measurement{timestamp: 1567029708 + i * 10 + i % 7, count: 1 + i % 3, value: (i as f64).sin()})
+            .chain([Measurement{timestamp: u64::MAX, count: 0, value: f64::from_bits(0x7ff0000000000002)},
+                    Measurement{timestamp: 0, count: u64::MAX, value: -0.0}])
+            .collect();
+
+        let mut encoder = StreamEncoder::new(Vec::new());
+
+        for (i, m) in measures.iter().enumerate() {
+            encoder.push(m).unwrap();
+
+            // Only the partial byte is held back
+            assert_eq!(encoder.writer.len(), encoder.byte_len() - (encoder.metadata.buf_offbits > 0) as usize);
+            assert_eq!(encoder.measurement_count(), i + 1);
+        }
+
+        let len = encoder.byte_len();
+        let bytes = encoder.finish().unwrap();
+
+        assert_eq!(bytes.len(), len);
+        assert_eq!(bytes, Block::encode(&measures).unwrap().data);
+
+        assert!(StreamEncoder::new(Vec::new()).finish().unwrap().is_empty());
+    }
+
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WriteZero, "full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stream_encoder_io_error()
+    {
+        let mut encoder = StreamEncoder::new(Full);
+        let result = encoder.push(&Measurement{timestamp: 1567029708, count: 1, value: 1.0});
+        assert!(matches!(result, Err(WriteError::Io(ref e)) if e.kind() == io::ErrorKind::WriteZero));
+    }
+}
@@ -0,0 +1,168 @@
+// One error type over every codec and container, for callers that handle them alike. A
+// service can log the Display form, which names the measurement, field and bit, and answer
+// with status_code().
+//
+// The io::Read and io::Write codecs' ReadError and WriteError are left out: they carry an
+// io::Error, which is neither Clone nor PartialEq, and a failing socket or disk is no verdict
+// on the data. Their Block, Truncated/Corrupt and Encoder cases hold the errors below for
+// callers to convert. The ingest protocols' errors are left out too, since each listener
+// answers a bad payload the way its protocol does, like InfluxDB's JSON error body.
+
+use std::error;
+use std::fmt;
+
+use super::block::BlockError;
+use super::codec::beringei::StreamError;
+use super::codec::decoder::DecoderError;
+use super::codec::encoder::EncoderError;
+use super::codec::m3tsz::SegmentError;
+use super::codec::prometheus::ChunkError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    Encoder(EncoderError),
+    Decoder(DecoderError),
+    Block(BlockError),
+    Chunk(ChunkError),
+    Stream(StreamError),
+    Segment(SegmentError)
+}
+
+impl Error {
+    // The input ended early, as opposed to being corrupt or unrepresentable
+    pub fn is_truncated(&self) -> bool {
+        match self {
+            Error::Encoder(_) => false,
+            Error::Decoder(e) => e.is_truncated(),
+            Error::Block(e) => *e == BlockError::Truncated,
+            Error::Chunk(e) => e.is_truncated(),
+            Error::Stream(e) => e.is_truncated(),
+            Error::Segment(e) => e.is_truncated()
+        }
+    }
+
+    // Bad input is the client's fault (400), as are measurements a format can't hold (422).
+    // Encoding into our own buffers only fails on a bug or a full buffer (500).
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Error::Encoder(_) => 500,
+            Error::Decoder(_) => 400,
+            Error::Block(BlockError::UnsupportedVersion(_)) => 415,
+            Error::Block(_) => 400,
+            Error::Chunk(ChunkError::TooManySamples(_)) => 413,
+            Error::Chunk(ChunkError::TimestampOutOfRange { .. }) => 422,
+            Error::Chunk(_) => 400,
+            Error::Stream(StreamError::TimestampOutOfRange { .. }) |
+            Error::Stream(StreamError::DeltaOfDeltaOutOfRange { .. }) => 422,
+            Error::Stream(_) => 400,
+            Error::Segment(SegmentError::TimestampOutOfRange { .. }) |
+            Error::Segment(SegmentError::DeltaOfDeltaOutOfRange { .. }) => 422,
+            Error::Segment(SegmentError::UnsupportedTimeUnit(_)) => 415,
+            Error::Segment(_) => 400
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Encoder(e) => write!(f, "encoding failed: {}", e),
+            Error::Decoder(e) => write!(f, "decoding failed: {}", e),
+            Error::Block(e) => write!(f, "invalid block: {}", e),
+            Error::Chunk(e) => write!(f, "Prometheus chunk: {}", e),
+            Error::Stream(e) => write!(f, "Beringei stream: {}", e),
+            Error::Segment(e) => write!(f, "M3TSZ segment: {}", e)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Encoder(e) => Some(e),
+            Error::Decoder(e) => Some(e),
+            Error::Block(e) => Some(e),
+            Error::Chunk(e) => Some(e),
+            Error::Stream(e) => Some(e),
+            Error::Segment(e) => Some(e)
+        }
+    }
+}
+
+impl From<EncoderError> for Error {
+    fn from(e: EncoderError) -> Self {
+        Error::Encoder(e)
+    }
+}
+
+impl From<DecoderError> for Error {
+    fn from(e: DecoderError) -> Self {
+        Error::Decoder(e)
+    }
+}
+
+impl From<BlockError> for Error {
+    fn from(e: BlockError) -> Self {
+        Error::Block(e)
+    }
+}
+
+impl From<ChunkError> for Error {
+    fn from(e: ChunkError) -> Self {
+        Error::Chunk(e)
+    }
+}
+
+impl From<StreamError> for Error {
+    fn from(e: StreamError) -> Self {
+        Error::Stream(e)
+    }
+}
+
+impl From<SegmentError> for Error {
+    fn from(e: SegmentError) -> Self {
+        Error::Segment(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+    use super::super::Measurement;
+    use super::super::block::Block;
+    use super::super::codec::{beringei, m3tsz, prometheus};
+
+    #[test]
+    fn test_error_context()
+    {
+        let block = Block::encode(&[Measurement{timestamp: 100, count: 1, value: 1.0},
+                                    Measurement{timestamp: 110, count: 1, value: 2.5}]).unwrap();
+
+        // Cut the second measurement's value short
+        let truncated = Block { count: 2, data: block.data[..block.data.len() - 2].to_vec() };
+        let error: Error = truncated.measurements().find_map(Result::err).unwrap().into();
+
+        assert!(error.is_truncated());
+        assert_eq!(error.status_code(), 400);
+        assert!(error.source().is_some());
+        assert!(error.to_string().starts_with("decoding failed: measurement 1: value at bit 89: reading"), "{}", error);
+
+        let error: Error = Block::from_bytes(b"GTSZ\x02\x00").unwrap_err().into();
+        assert_eq!((error.status_code(), error.to_string().as_str()), (415, "invalid block: unsupported block version 2"));
+
+        let error: Error = prometheus::encode_chunk(&[Measurement{timestamp: u64::MAX, count: 1, value: 0.0}]).unwrap_err().into();
+        assert_eq!(error.status_code(), 422);
+
+        let error: Error = beringei::decode_stream(&[0x00], 1).unwrap_err().into();
+        assert!(error.is_truncated());
+
+        let error: Error = m3tsz::encode_segment(0, &[Measurement{timestamp: u64::MAX, count: 1, value: 0.0}]).unwrap_err().into();
+        assert_eq!(error.status_code(), 422);
+        assert!(error.to_string().starts_with("M3TSZ segment: "), "{}", error);
+
+        let error: Error = m3tsz::decode_segment(&[0x00]).unwrap_err().into();
+        assert!(error.is_truncated());
+    }
+}
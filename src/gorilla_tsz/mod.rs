@@ -1,7 +1,9 @@
 pub mod utils;
 pub mod codec;
+pub mod block;
+pub mod error;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Measurement {
     pub timestamp: u64,
     pub count: u64,
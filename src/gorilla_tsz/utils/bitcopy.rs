@@ -1,8 +1,27 @@
+use std::error;
+use std::fmt;
 use std::mem;
 use std::cmp::min;
 
-#[derive(Debug)]
-pub struct BitCopyError;
+// Copies that run off the end of a buffer, with the requested copy and the buffer size in bits
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitCopyError {
+    SourceOverrun { offset: usize, nbits: usize, len: usize },
+    DestinationOverrun { offset: usize, nbits: usize, len: usize }
+}
+
+impl fmt::Display for BitCopyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BitCopyError::SourceOverrun { offset, nbits, len } =>
+                write!(f, "reading {} bits at bit {} overruns the {} bit source", nbits, offset, len),
+            BitCopyError::DestinationOverrun { offset, nbits, len } =>
+                write!(f, "writing {} bits at bit {} overruns the {} bit destination", nbits, offset, len)
+        }
+    }
+}
+
+impl error::Error for BitCopyError {}
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum BitValue {
@@ -10,18 +29,15 @@ pub enum BitValue {
     One
 }
 
-fn bitmask_lower(bits: usize) -> Result<u8, BitCopyError>
+// Only ever called with at most a byte's worth of bits
+fn bitmask_lower(bits: usize) -> u8
 {
     let wordsizebits = mem::size_of::<u8>() * 8;
 
-    if bits > wordsizebits {
-        return Err(BitCopyError);
-    }
-
-    if bits == wordsizebits {
-        Ok(0xFF)
+    if bits >= wordsizebits {
+        0xFF
     } else {
-        Ok((0x01u8 << bits) - 1)
+        (0x01u8 << bits) - 1
     }
 }
 
@@ -52,6 +68,8 @@ pub fn read_bit(src_buf: &[u8], src_offbits: usize) -> Result<BitValue, BitCopyE
 pub fn copy(dst_buf: &mut [u8], src_buf: &[u8], nbits: usize,
             dst_offbits: usize, src_offbits: usize) -> Result<(), BitCopyError>
 {
+    let src_overrun = BitCopyError::SourceOverrun { offset: src_offbits, nbits, len: src_buf.len() * 8 };
+    let dst_overrun = BitCopyError::DestinationOverrun { offset: dst_offbits, nbits, len: dst_buf.len() * 8 };
 
     let mut dst_offbits = dst_offbits;
     let mut src_offbits = src_offbits;
@@ -79,11 +97,11 @@ pub fn copy(dst_buf: &mut [u8], src_buf: &[u8], nbits: usize,
         let src_idx = src_offbits / copybitsize;
 
         if src_idx >= src_buf.len() {
-            return Err(BitCopyError{});
+            return Err(src_overrun);
         }
 
         // We may mask more bits than we need
-        let src_mask = bitmask_lower(src_bits)?;
+        let src_mask = bitmask_lower(src_bits);
 
         // Mask out bits and shift to only keep those we're copying
         let byte = (src_buf[src_idx] & src_mask) >> (src_bits - bits_to_copy);
@@ -99,25 +117,25 @@ pub fn copy(dst_buf: &mut [u8], src_buf: &[u8], nbits: usize,
         let dst_idx = dst_offbits / copybitsize;
 
         if dst_idx >= dst_buf.len() {
-            return Err(BitCopyError{});
+            return Err(dst_overrun);
         }
 
-        let byte_mask = bitmask_lower(dst_copy_bits)?;
+        let byte_mask = bitmask_lower(dst_copy_bits);
 
         if dst_copy_bits <= dst_bits {
             if dst_idx >= dst_buf.len() {
-                return Err(BitCopyError{});
+                return Err(dst_overrun);
             }
 
-            dst_buf[dst_idx] = (dst_buf[dst_idx] & !bitmask_lower(dst_bits)?) |
+            dst_buf[dst_idx] = (dst_buf[dst_idx] & !bitmask_lower(dst_bits)) |
                 ((byte & byte_mask) << (dst_bits - dst_copy_bits));
         } else {
             // We'll copy two words here
             if (dst_idx + 1) >= dst_buf.len() {
-                return Err(BitCopyError{});
+                return Err(dst_overrun);
             }
 
-            dst_buf[dst_idx] = (dst_buf[dst_idx] & !bitmask_lower(dst_bits)?) |
+            dst_buf[dst_idx] = (dst_buf[dst_idx] & !bitmask_lower(dst_bits)) |
                 ((byte & byte_mask) >> (dst_copy_bits - dst_bits));
 
             // Move the remaining bits into the top of the next word
@@ -148,13 +166,13 @@ mod tests {
 
         in_byte[0] = 18;
 
-        for i in 0..offset_bits {
+        for _ in 0..offset_bits {
             assert_eq!(write_bit(&mut buf, dst_offbits, BitValue::One).ok(), Some(()));
             dst_offbits += 1;
         }
         assert_eq!(copy(&mut buf, &in_byte, 6, dst_offbits, 8 - 6).ok(), Some(()));
 
-        for i in 0..offset_bits {
+        for _ in 0..offset_bits {
             assert_eq!(read_bit(&buf, src_offbits).ok(), Some(BitValue::One));
             src_offbits += 1;
         }
@@ -168,34 +186,28 @@ mod tests {
         const COUNT: usize = 10;
         let mut buf = [0u8; COUNT];
 
-        let mut dst_off = 0;
-        for i in 0..(COUNT * 8) {
+        for dst_off in 0..(COUNT * 8) {
             let mut b = [0u8];
 
-            if (i % 2) != 0 {
+            if (dst_off % 2) != 0 {
                 b[0] |= 1 << 7;
             }
 
             let r = copy(&mut buf, &b, 1, dst_off, 0);
             assert_eq!(r.ok(), Some(()));
-
-            dst_off += 1;
         }
 
-        let mut src_off = 0;
-        for i in 0..(COUNT * 8) {
+        for src_off in 0..(COUNT * 8) {
             let mut b = [0u8];
 
             let r = copy(&mut b, &buf, 1, 0, src_off);
             assert_eq!(r.ok(), Some(()));
 
-            if (i % 2) != 0 {
+            if (src_off % 2) != 0 {
                 assert_eq!(b[0], 1 << 7);
             } else {
                 assert_eq!(b[0], 0u8);
             }
-
-            src_off += 1;
         }
     }
 
@@ -225,21 +237,15 @@ mod tests {
             let sz = encode(*value, &mut int_buf).unwrap();
 
             encoded.push((*value, sz));
-            match copy(&mut buf, &int_buf, sz * 8, dst_offbits, 0) {
-                Err(e) => assert!(false),
-                Ok(()) => assert!(true)
-            };
+            assert_eq!(copy(&mut buf, &int_buf, sz * 8, dst_offbits, 0).ok(), Some(()));
 
             dst_offbits += sz * 8;
 
-            match weave_bits {
-                Some(num) => {
-                    for _ in 0..num {
-                        assert_eq!(write_bit(&mut buf, dst_offbits, BitValue::One).ok(), Some(()));
-                        dst_offbits += 1;
-                    }
-                },
-                None => {}
+            if let Some(num) = weave_bits {
+                for _ in 0..num {
+                    assert_eq!(write_bit(&mut buf, dst_offbits, BitValue::One).ok(), Some(()));
+                    dst_offbits += 1;
+                }
             }
         }
 
@@ -247,24 +253,28 @@ mod tests {
         let mut src_offbits = 0;
         for (value, sz) in encoded {
 
-            match copy(&mut int_buf, &buf, sz * 8, 0, src_offbits) {
-                Err(e) => assert!(false),
-                Ok(()) => {
-                    assert_eq!(decode(&int_buf).ok(), Some((value, sz)))
-                }
-            };
+            assert_eq!(copy(&mut int_buf, &buf, sz * 8, 0, src_offbits).ok(), Some(()));
+            assert_eq!(decode(&int_buf).ok(), Some((value, sz)));
 
             src_offbits += sz * 8;
 
-            match weave_bits {
-                Some(num) => {
-                    for _ in 0..num {
-                        assert_eq!(read_bit(&buf, src_offbits).ok(), Some(BitValue::One));
-                        src_offbits += 1;
-                    }
-                },
-                None => {}
+            if let Some(num) = weave_bits {
+                for _ in 0..num {
+                    assert_eq!(read_bit(&buf, src_offbits).ok(), Some(BitValue::One));
+                    src_offbits += 1;
+                }
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_bit_copy_overrun() {
+        let mut dst = [0u8; 2];
+
+        assert_eq!(copy(&mut dst, &[0xff; 2], 12, 0, 8),
+                   Err(BitCopyError::SourceOverrun { offset: 8, nbits: 12, len: 16 }));
+        assert_eq!(copy(&mut dst, &[0xff; 4], 12, 6, 0),
+                   Err(BitCopyError::DestinationOverrun { offset: 6, nbits: 12, len: 16 }));
+        assert_eq!(read_bit(&[], 0), Err(BitCopyError::SourceOverrun { offset: 0, nbits: 1, len: 0 }));
+    }
+}
@@ -0,0 +1,108 @@
+use super::bitcopy;
+use super::bitcopy::{BitCopyError, BitValue};
+
+// Appends MSB-first bit fields to a growable buffer
+pub struct BitWriter {
+    buf: Vec<u8>,
+    offbits: usize
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter { buf: Vec::new(), offbits: 0 }
+    }
+
+    fn reserve(&mut self, nbits: usize) {
+        let len = (self.offbits + nbits).div_ceil(8);
+
+        if len > self.buf.len() {
+            self.buf.resize(len, 0);
+        }
+    }
+
+    // Writes the low `nbits` bits of `value`
+    pub fn write(&mut self, value: u64, nbits: usize) -> Result<(), BitCopyError> {
+        self.reserve(nbits);
+        bitcopy::copy(&mut self.buf, &value.to_be_bytes(), nbits, self.offbits, 64 - nbits)?;
+        self.offbits += nbits;
+        Ok(())
+    }
+
+    pub fn write_bit(&mut self, bit: BitValue) -> Result<(), BitCopyError> {
+        self.reserve(1);
+        bitcopy::write_bit(&mut self.buf, self.offbits, bit)?;
+        self.offbits += 1;
+        Ok(())
+    }
+
+    // The written bits, zero padded to a whole byte
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for BitWriter {
+    fn default() -> Self {
+        BitWriter::new()
+    }
+}
+
+// Reads MSB-first bit fields from a slice
+pub struct BitReader<'a> {
+    buf: &'a [u8],
+    offbits: usize
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(buf: &'a [u8]) -> BitReader<'a> {
+        BitReader { buf, offbits: 0 }
+    }
+
+    // Reads `nbits` (at most 64) into the low bits of the result
+    pub fn read(&mut self, nbits: usize) -> Result<u64, BitCopyError> {
+        let value = self.peek(nbits)?;
+        self.offbits += nbits;
+        Ok(value)
+    }
+
+    // Like read(), but leaves the bits to be read again
+    pub fn peek(&self, nbits: usize) -> Result<u64, BitCopyError> {
+        let mut bytes = [0u8; 8];
+
+        bitcopy::copy(&mut bytes, self.buf, nbits, 64 - nbits, self.offbits)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    pub fn read_bit(&mut self) -> Result<BitValue, BitCopyError> {
+        let bit = bitcopy::read_bit(self.buf, self.offbits)?;
+        self.offbits += 1;
+        Ok(bit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitstream()
+    {
+        let mut writer = BitWriter::new();
+        writer.write(0b101, 3).unwrap();
+        writer.write_bit(BitValue::One).unwrap();
+        writer.write(u64::MAX, 64).unwrap();
+        writer.write(0x1234, 13).unwrap();
+
+        let bytes = writer.into_bytes();
+        assert_eq!(bytes.len(), 11);
+        assert_eq!(bytes[0], 0b1011_1111);
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.peek(4).ok(), Some(0b1011));
+        assert_eq!(reader.read(3).ok(), Some(0b101));
+        assert_eq!(reader.read_bit().ok(), Some(BitValue::One));
+        assert_eq!(reader.read(64).ok(), Some(u64::MAX));
+        assert_eq!(reader.read(13).ok(), Some(0x1234));
+        assert!(reader.read(8).is_err());
+    }
+}
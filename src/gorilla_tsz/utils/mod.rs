@@ -1,2 +1,3 @@
 pub mod bitcopy;
+pub mod bitstream;
 pub mod varint;
\ No newline at end of file
@@ -1,6 +1,28 @@
 
-#[derive(Debug)]
-pub struct VarIntError;
+use std::error;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VarIntError {
+    // Encoding needs more room than the buffer's length
+    BufferTooSmall(usize),
+    // Ran out of input after this many bytes, before the last byte of the varint
+    Truncated(usize),
+    // More than 10 bytes, which can't fit in a u64
+    TooLong
+}
+
+impl fmt::Display for VarIntError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VarIntError::BufferTooSmall(len) => write!(f, "varint does not fit in {} bytes", len),
+            VarIntError::Truncated(len) => write!(f, "varint truncated after {} bytes", len),
+            VarIntError::TooLong => write!(f, "varint longer than 10 bytes")
+        }
+    }
+}
+
+impl error::Error for VarIntError {}
 
 pub fn encode(val: u64, buf: &mut [u8]) -> Result<usize, VarIntError> {
     let mut byte: u8;
@@ -9,7 +31,7 @@ pub fn encode(val: u64, buf: &mut [u8]) -> Result<usize, VarIntError> {
     let mut val = val;
     while val >= 128 {
         if idx >= buf.len() {
-            return Err(VarIntError{});
+            return Err(VarIntError::BufferTooSmall(buf.len()));
         }
 
         byte = (0x80 | (val & 0x7f)) as u8;
@@ -19,7 +41,7 @@ pub fn encode(val: u64, buf: &mut [u8]) -> Result<usize, VarIntError> {
     }
 
     if idx >= buf.len() {
-        return Err(VarIntError{});
+        return Err(VarIntError::BufferTooSmall(buf.len()));
     }
 
     byte = val as u8;
@@ -34,8 +56,12 @@ pub fn decode(buf: &[u8]) -> Result<(u64, usize), VarIntError> {
     let mut shift = 0u64;
 
     loop {
+        // Anything past the 10th byte would shift out of a u64
+        if shift > 63 {
+            return Err(VarIntError::TooLong);
+        }
         if idx >= buf.len() {
-            return Err(VarIntError{})
+            return Err(VarIntError::Truncated(idx));
         }
         let byte = buf[idx];
         val |= ((byte & 0x7f) as u64) << shift;
@@ -53,14 +79,14 @@ pub fn encode_zigzag(val: i64) -> u64 {
     let uval_l = val as u64;
     let uval_r = (val >> 63) as u64;
 
-    return (uval_l << 1) ^ uval_r;
+    (uval_l << 1) ^ uval_r
 }
 
 pub fn decode_zigzag(val: u64) -> i64 {
     let ival_l = (val >> 1) as i64;
     let ival_r = (val & 1) as i64;
 
-    return ival_l ^ -(ival_r);
+    ival_l ^ -(ival_r)
 }
 
 #[test]
@@ -80,8 +106,10 @@ fn test_varint_encoding() {
 
     let mut buf = [0u8; 10];
 
-    assert_eq!(encode(1 as u64, &mut buf).unwrap(), 1);
-    assert!(decode(&vec![128]).is_err());
+    assert_eq!(encode(1u64, &mut buf).unwrap(), 1);
+    assert_eq!(encode(300u64, &mut buf[..1]), Err(VarIntError::BufferTooSmall(1)));
+    assert_eq!(decode(&[128]), Err(VarIntError::Truncated(1)));
+    assert_eq!(decode(&[0xff; 11]), Err(VarIntError::TooLong));
 
     assert_eq!(encode_zigzag(-1), 1);
     assert_eq!(decode_zigzag(encode_zigzag(-5)), -5);
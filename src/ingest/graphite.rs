@@ -0,0 +1,213 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::SyncSender;
+use std::thread;
+
+use crate::gorilla_tsz::Measurement;
+use super::{SeriesKey, Sample, Counters, Line, accept_failed, lines, now_millis};
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseError {
+    MissingFields,
+    TrailingData(String),
+    InvalidPath(String),
+    InvalidValue(String),
+    InvalidTimestamp(String)
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::MissingFields => write!(f, "expected <path> <value> <timestamp>"),
+            ParseError::TrailingData(d) => write!(f, "trailing data: {}", d),
+            ParseError::InvalidPath(p) => write!(f, "invalid path: {}", p),
+            ParseError::InvalidValue(v) => write!(f, "invalid value: {}", v),
+            ParseError::InvalidTimestamp(ts) => write!(f, "invalid timestamp: {}", ts)
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+// Parses one plaintext protocol line, `<path> <value> <timestamp>`, with the timestamp in
+// seconds. The dotted path becomes the series name as is. Graphite 1.1 style tags
+// (`path;tag=value;...`) become labels. A timestamp of -1 means `now`, in milliseconds.
+pub fn parse_line(line: &str, now: u64) -> Result<Sample, ParseError>
+{
+    let mut parts = line.split_whitespace();
+
+    let (path, value, timestamp) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(path), Some(value), Some(timestamp)) => (path, value, timestamp),
+        _ => return Err(ParseError::MissingFields)
+    };
+
+    let rest: Vec<&str> = parts.collect();
+    if !rest.is_empty() {
+        return Err(ParseError::TrailingData(rest.join(" ")));
+    }
+
+    let mut tags = path.split(';');
+    let name = tags.next().unwrap_or_default();
+
+    if name.is_empty() || name.starts_with('.') || name.ends_with('.') || name.contains("..") {
+        return Err(ParseError::InvalidPath(path.to_string()));
+    }
+
+    let mut labels = Vec::new();
+    for tag in tags {
+        match tag.split_once('=') {
+            Some((k, v)) if !k.is_empty() && !v.is_empty() => labels.push((k.to_string(), v.to_string())),
+            _ => return Err(ParseError::InvalidPath(path.to_string()))
+        }
+    }
+
+    let value = value.parse::<f64>().map_err(|_| ParseError::InvalidValue(value.to_string()))?;
+
+    // Some clients send fractional seconds
+    let timestamp = match timestamp.parse::<f64>() {
+        Ok(-1.0) => now,
+        Ok(ts) if ts >= 0.0 && ts < (u64::MAX / 1000) as f64 => (ts * 1000.0) as u64,
+        _ => return Err(ParseError::InvalidTimestamp(timestamp.to_string()))
+    };
+
+    Ok(Sample {
+        key: SeriesKey::new(name.to_string(), labels),
+        measurement: Measurement { timestamp, count: 1, value }
+    })
+}
+
+// Reads lines from a single client until EOF, sending every valid sample to `sink`.
+//
+// The sink is a bounded channel: once it's full, sending blocks, we stop reading from the
+// client, and TCP flow control pushes back on the sender. Malformed lines are counted
+// and dropped, as carbon does, and so are lines too long to buffer.
+pub fn handle_connection<R: BufRead>(reader: R, sink: &SyncSender<Sample>, counters: &Counters) -> io::Result<()>
+{
+    for line in lines(reader) {
+        let line = match line? {
+            Line::Complete(line) => line,
+            Line::TooLong => {
+                counters.malformed.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        match parse_line(line, now_millis()) {
+            Ok(sample) => {
+                counters.received.fetch_add(1, Ordering::Relaxed);
+
+                if sink.send(sample).is_err() {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "sample receiver closed"));
+                }
+            },
+            Err(_) => {
+                counters.malformed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Accepts plaintext protocol connections forever, handling each on its own thread. A
+// failed accept (eg. out of file descriptors) only costs that connection, and is counted
+// in accept_errors.
+pub fn serve(listener: TcpListener, sink: SyncSender<Sample>, counters: Arc<Counters>) -> io::Result<()>
+{
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => {
+                accept_failed(&counters);
+                continue;
+            }
+        };
+        let sink = sink.clone();
+        let counters = counters.clone();
+
+        thread::spawn(move || {
+            let _ = handle_connection(BufReader::new(stream), &sink, &counters);
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn test_parse_line()
+    {
+        let sample = parse_line("servers.web01.cpu.load 1.25 1567029708", 0).unwrap();
+        assert_eq!(sample.key, SeriesKey::new("servers.web01.cpu.load".to_string(), Vec::new()));
+        assert_eq!(sample.measurement, Measurement{timestamp: 1567029708000, count: 1, value: 1.25});
+
+        let sample = parse_line("disk.used;host=web01;dc=east 42 1567029708.5", 0).unwrap();
+        assert_eq!(sample.key, SeriesKey::new("disk.used".to_string(),
+                                              vec![("dc".to_string(), "east".to_string()), ("host".to_string(), "web01".to_string())]));
+        assert_eq!(sample.measurement.timestamp, 1567029708500);
+
+        assert_eq!(parse_line("a.b 1 -1", 77).unwrap().measurement.timestamp, 77);
+
+        assert_eq!(parse_line("a.b 1", 0), Err(ParseError::MissingFields));
+        assert_eq!(parse_line("a..b 1 1", 0), Err(ParseError::InvalidPath("a..b".to_string())));
+        assert_eq!(parse_line("a.b;host 1 1", 0), Err(ParseError::InvalidPath("a.b;host".to_string())));
+        assert_eq!(parse_line("a.b x 1", 0), Err(ParseError::InvalidValue("x".to_string())));
+        assert_eq!(parse_line("a.b 1 yesterday", 0), Err(ParseError::InvalidTimestamp("yesterday".to_string())));
+        assert_eq!(parse_line("a.b 1 1 2", 0), Err(ParseError::TrailingData("2".to_string())));
+        assert_eq!(parse_line("a.b x 1", 0).unwrap_err().to_string(), "invalid value: x");
+    }
+
+    #[test]
+    fn test_handle_connection()
+    {
+        let (tx, rx) = sync_channel(16);
+        let counters = Counters::default();
+        let long = format!("a.f 5 50{}", " ".repeat(100 * 1024));
+        let input = format!("a.b 1 10\n\ngarbage\r\na.c 2 20\r\n{}\na.e 3\na.d 4 40", long);
+
+        handle_connection(Cursor::new(input), &tx, &counters).unwrap();
+        drop(tx);
+
+        let samples: Vec<Sample> = rx.iter().collect();
+        assert_eq!(samples.iter().map(|s| s.key.name()).collect::<Vec<_>>(), vec!["a.b", "a.c", "a.d"]);
+        assert_eq!(counters.received.load(Ordering::Relaxed), 3);
+        assert_eq!(counters.malformed.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_serve()
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let counters = Arc::new(Counters::default());
+        let (tx, rx) = sync_channel(1);
+
+        let serve_counters = counters.clone();
+        thread::spawn(move || serve(listener, tx, serve_counters));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"app.requests 10 1567029708\napp.requests 12 1567029718\n").unwrap();
+        drop(stream);
+
+        let values: Vec<f64> = rx.iter().take(2).map(|s| s.measurement.value).collect();
+        assert_eq!(values, vec![10.0, 12.0]);
+    }
+}
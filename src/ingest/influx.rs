@@ -0,0 +1,405 @@
+use std::error;
+use std::fmt;
+use std::str;
+use std::str::FromStr;
+
+use serde_json::json;
+
+use crate::gorilla_tsz::Measurement;
+use super::{SeriesKey, Sample, now_millis};
+
+// Timestamp precision of a payload, as in the `precision` parameter of InfluxDB's /write API
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds
+}
+
+impl Precision {
+    fn to_millis(self, timestamp: u64) -> Option<u64> {
+        match self {
+            Precision::Nanoseconds => Some(timestamp / 1_000_000),
+            Precision::Microseconds => Some(timestamp / 1_000),
+            Precision::Milliseconds => Some(timestamp),
+            Precision::Seconds => timestamp.checked_mul(1_000)
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ns" | "n" => Ok(Precision::Nanoseconds),
+            "us" | "u" => Ok(Precision::Microseconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "s" => Ok(Precision::Seconds),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseError {
+    MissingMeasurement,
+    MissingFields,
+    NoNumericFields,
+    InvalidTag(String),
+    InvalidField(String),
+    InvalidTimestamp(String),
+    UnterminatedString,
+    TrailingData(String)
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::MissingMeasurement => write!(f, "missing measurement"),
+            ParseError::MissingFields => write!(f, "missing fields"),
+            ParseError::NoNumericFields => write!(f, "no numeric fields"),
+            ParseError::InvalidTag(t) => write!(f, "invalid tag: {}", t),
+            ParseError::InvalidField(v) => write!(f, "invalid field: {}", v),
+            ParseError::InvalidTimestamp(ts) => write!(f, "invalid timestamp: {}", ts),
+            ParseError::UnterminatedString => write!(f, "unterminated string"),
+            ParseError::TrailingData(d) => write!(f, "trailing data: {}", d)
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct LineError {
+    // 1-based line number within the payload
+    pub line: usize,
+    pub error: ParseError
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+impl error::Error for LineError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// Splits on `sep`, skipping backslash escaped characters and, if `quotes` is set, anything
+// inside double quotes
+fn split_unescaped(s: &str, sep: char, quotes: bool) -> Result<Vec<&str>, ParseError>
+{
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+
+    for (idx, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if quotes && c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..idx]);
+            start = idx + c.len_utf8();
+        }
+    }
+
+    if quoted {
+        return Err(ParseError::UnterminatedString);
+    }
+
+    parts.push(&s[start..]);
+    Ok(parts)
+}
+
+fn unescape(s: &str) -> String
+{
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek() {
+                if matches!(next, ',' | '=' | ' ' | '"' | '\\') {
+                    out.push(next);
+                    chars.next();
+                    continue;
+                }
+            }
+        }
+
+        out.push(c);
+    }
+
+    out
+}
+
+fn split_pair(s: &str) -> Option<(String, &str)>
+{
+    match split_unescaped(s, '=', false) {
+        Ok(ref parts) if parts.len() >= 2 && !parts[0].is_empty() => {
+            Some((unescape(parts[0]), &s[parts[0].len() + 1..]))
+        },
+        _ => None
+    }
+}
+
+// Returns None for field types that are valid line protocol but can't be stored as a
+// float, ie. strings and booleans
+fn parse_field_value(value: &str) -> Result<Option<f64>, ()>
+{
+    if value.starts_with('"') {
+        return Ok(None);
+    }
+
+    if matches!(value, "t" | "T" | "true" | "True" | "TRUE" | "f" | "F" | "false" | "False" | "FALSE") {
+        return Ok(None);
+    }
+
+    if let Some(int) = value.strip_suffix('i') {
+        return int.parse::<i64>().map(|v| Some(v as f64)).map_err(|_| ());
+    }
+
+    if let Some(uint) = value.strip_suffix('u') {
+        return uint.parse::<u64>().map(|v| Some(v as f64)).map_err(|_| ());
+    }
+
+    // Rust also accepts "inf" and "NaN", which line protocol does not
+    match value.chars().next() {
+        Some(c) if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' => {
+            value.parse::<f64>().map(Some).map_err(|_| ())
+        },
+        _ => Err(())
+    }
+}
+
+// Parses a single line into one sample per numeric field. Each field becomes its own
+// series named `<measurement>_<field>`, labelled with the line's tags. Lines without a
+// timestamp use `default_timestamp`, in milliseconds.
+pub fn parse_line(line: &str, precision: Precision, default_timestamp: u64) -> Result<Vec<Sample>, ParseError>
+{
+    let sections = split_unescaped(line.trim_end(), ' ', true)?;
+
+    let mut labels = Vec::new();
+    let series = split_unescaped(sections[0], ',', false)?;
+
+    let measurement = unescape(series[0]);
+    if measurement.is_empty() {
+        return Err(ParseError::MissingMeasurement);
+    }
+
+    for tag in &series[1..] {
+        match split_pair(tag) {
+            Some((key, value)) if !value.is_empty() => labels.push((key, unescape(value))),
+            _ => return Err(ParseError::InvalidTag(tag.to_string()))
+        }
+    }
+
+    let fields = match sections.get(1) {
+        Some(fields) if !fields.is_empty() => split_unescaped(fields, ',', true)?,
+        _ => return Err(ParseError::MissingFields)
+    };
+
+    let timestamp = match sections.get(2) {
+        None => default_timestamp,
+        Some(ts) => ts.parse::<u64>().ok()
+            .and_then(|ts| precision.to_millis(ts))
+            .ok_or_else(|| ParseError::InvalidTimestamp(ts.to_string()))?
+    };
+
+    if sections.len() > 3 {
+        return Err(ParseError::TrailingData(sections[3..].join(" ")));
+    }
+
+    let mut samples = Vec::new();
+
+    for field in fields {
+        let (key, value) = split_pair(field).ok_or_else(|| ParseError::InvalidField(field.to_string()))?;
+
+        match parse_field_value(value) {
+            Err(()) => return Err(ParseError::InvalidField(field.to_string())),
+            Ok(None) => {},
+            Ok(Some(value)) => {
+                let key = SeriesKey::new(format!("{}_{}", measurement, key), labels.clone());
+
+                samples.push(Sample { key, measurement: Measurement { timestamp, count: 1, value } });
+            }
+        }
+    }
+
+    if samples.is_empty() {
+        return Err(ParseError::NoNumericFields);
+    }
+
+    Ok(samples)
+}
+
+// Parses a full line protocol payload. Bad lines don't stop the rest of the payload from
+// being parsed; each one is reported with its line number instead. Blank lines and
+// comments are skipped.
+pub fn parse(input: &str, precision: Precision, default_timestamp: u64) -> (Vec<Sample>, Vec<LineError>)
+{
+    let mut samples = Vec::new();
+    let mut errors = Vec::new();
+
+    for (idx, line) in input.lines().enumerate() {
+        let line = line.trim_start();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_line(line, precision, default_timestamp) {
+            Ok(mut parsed) => samples.append(&mut parsed),
+            Err(error) => errors.push(LineError { line: idx + 1, error })
+        }
+    }
+
+    (samples, errors)
+}
+
+pub struct WriteResponse {
+    pub samples: Vec<Sample>,
+    pub status: u16,
+    // None for a 204
+    pub body: Option<String>
+}
+
+fn error_response(message: &str) -> WriteResponse
+{
+    WriteResponse { samples: Vec::new(), status: 400, body: Some(json!({"error": message}).to_string()) }
+}
+
+// Handles an HTTP /write body, with `precision` taken from the query string. Like InfluxDB,
+// the good lines of a partly bad payload are still returned for storage, and the response
+// is a 400 "partial write" naming the first bad line.
+pub fn write(body: &[u8], precision: Option<&str>) -> WriteResponse
+{
+    // InfluxDB defaults to nanoseconds when no precision is given
+    let precision = match precision.map(str::parse) {
+        None => Precision::Nanoseconds,
+        Some(Ok(precision)) => precision,
+        Some(Err(())) => return error_response(&format!("invalid precision {:?}", precision.unwrap_or_default()))
+    };
+
+    let body = match str::from_utf8(body) {
+        Ok(body) => body,
+        Err(_) => return error_response("body is not valid UTF-8")
+    };
+
+    let (samples, errors) = parse(body, precision, now_millis());
+
+    match errors.first() {
+        None => WriteResponse { samples, status: 204, body: None },
+        Some(first) if samples.is_empty() => error_response(&format!("unable to parse {}", first)),
+        Some(first) => WriteResponse {
+            samples,
+            ..error_response(&format!("partial write: unable to parse {} dropped={}", first, errors.len()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::key;
+
+    #[test]
+    fn test_parse_line()
+    {
+        let samples = parse_line("cpu,region=us-west,host=web01 usage_idle=91.5,threads=12i,up=true,state=\"ok go\" 1567029708000000000",
+                                 Precision::Nanoseconds, 0).unwrap();
+
+        let labels = [("host", "web01"), ("region", "us-west")];
+        assert_eq!(samples, vec![
+            Sample{key: key("cpu_usage_idle", &labels), measurement: Measurement{timestamp: 1567029708000, count: 1, value: 91.5}},
+            Sample{key: key("cpu_threads", &labels), measurement: Measurement{timestamp: 1567029708000, count: 1, value: 12.0}}
+        ]);
+    }
+
+    #[test]
+    fn test_escapes_and_defaults()
+    {
+        let samples = parse_line("disk\\ io,path=C:\\\\data,label=a\\,b\\=c free=1e3,used=2u", Precision::Seconds, 42).unwrap();
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].key, key("disk io_free", &[("label", "a,b=c"), ("path", "C:\\data")]));
+        assert_eq!(samples[0].measurement, Measurement{timestamp: 42, count: 1, value: 1000.0});
+    }
+
+    #[test]
+    fn test_precision()
+    {
+        let parse_ts = |ts: &str, p: Precision| parse_line(&format!("m v=1 {}", ts), p, 0).map(|s| s[0].measurement.timestamp);
+
+        assert_eq!(parse_ts("1567029708123456789", Precision::Nanoseconds), Ok(1567029708123));
+        assert_eq!(parse_ts("1567029708123456", Precision::Microseconds), Ok(1567029708123));
+        assert_eq!(parse_ts("1567029708123", Precision::Milliseconds), Ok(1567029708123));
+        assert_eq!(parse_ts("1567029708", Precision::Seconds), Ok(1567029708000));
+        assert_eq!(parse_ts("-1", Precision::Seconds), Err(ParseError::InvalidTimestamp("-1".to_string())));
+        assert_eq!(parse_ts("18446744073709551615", Precision::Seconds),
+                   Err(ParseError::InvalidTimestamp("18446744073709551615".to_string())));
+
+        assert_eq!("ms".parse::<Precision>(), Ok(Precision::Milliseconds));
+        assert!("h".parse::<Precision>().is_err());
+    }
+
+    #[test]
+    fn test_per_line_errors()
+    {
+        let input = "# comment\n\
+                     cpu value=1 10\n\
+                     cpu\n\
+                     cpu,host value=1\n\
+                     cpu value=abc\n\
+                     cpu value=\"open\n\
+                     \n\
+                     cpu state=\"up\"\n\
+                     cpu value=2 20 extra\n\
+                     cpu value=3 30\n";
+
+        let (samples, errors) = parse(input, Precision::Seconds, 0);
+
+        assert_eq!(samples.iter().map(|s| s.measurement.timestamp).collect::<Vec<_>>(), vec![10000, 30000]);
+        assert_eq!(errors, vec![
+            LineError{line: 3, error: ParseError::MissingFields},
+            LineError{line: 4, error: ParseError::InvalidTag("host".to_string())},
+            LineError{line: 5, error: ParseError::InvalidField("value=abc".to_string())},
+            LineError{line: 6, error: ParseError::UnterminatedString},
+            LineError{line: 8, error: ParseError::NoNumericFields},
+            LineError{line: 9, error: ParseError::TrailingData("extra".to_string())}
+        ]);
+    }
+
+    #[test]
+    fn test_write()
+    {
+        let response = write(b"cpu,host=a value=1 1567029708\ncpu,host=b value=2 1567029708\n", Some("s"));
+        assert_eq!((response.status, response.body), (204, None));
+        assert_eq!(response.samples.iter().map(|s| s.measurement.timestamp).collect::<Vec<_>>(), vec![1567029708000; 2]);
+
+        let response = write(b"cpu value=1 1567029708000000000", None);
+        assert_eq!(response.samples[0].measurement.timestamp, 1567029708000);
+
+        let response = write(b"cpu value=1 10\ncpu\ncpu value=x 30\n", Some("s"));
+        assert_eq!(response.status, 400);
+        assert_eq!(response.samples.len(), 1);
+        assert_eq!(response.body.unwrap(), r#"{"error":"partial write: unable to parse line 2: missing fields dropped=2"}"#);
+
+        let response = write(b"cpu", None);
+        assert_eq!((response.status, response.body.unwrap()), (400, r#"{"error":"unable to parse line 1: missing fields"}"#.to_string()));
+
+        let response = write(b"cpu value=1", Some("h"));
+        assert_eq!((response.status, response.body.unwrap()), (400, r#"{"error":"invalid precision \"h\""}"#.to_string()));
+
+        assert_eq!(write(b"cpu value=1 \xff", None).status, 400);
+    }
+}
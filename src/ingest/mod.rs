@@ -0,0 +1,265 @@
+// Parsers for the wire protocols we accept measurements in.
+//
+// Every protocol is mapped onto the same model: a SeriesKey identifying the series and a
+// Measurement to append to it. Timestamps are normalized to milliseconds since the Unix
+// epoch regardless of the precision used on the wire, and each parsed sample carries a
+// count of 1. StatsD is the exception: it aggregates updates over a flush interval, and its
+// samples carry the number of updates they were aggregated from.
+
+pub mod graphite;
+pub mod influx;
+pub mod opentsdb;
+pub mod otlp;
+pub mod remote_write;
+pub mod statsd;
+
+mod protobuf;
+mod snappy;
+#[cfg(test)]
+pub(crate) mod testing;
+
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Read};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::gorilla_tsz::Measurement;
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct SeriesKey {
+    name: String,
+    labels: Vec<(String, String)>
+}
+
+impl SeriesKey {
+    // Labels are kept sorted by name, so the same label set always yields the same key
+    pub fn new(name: String, mut labels: Vec<(String, String)>) -> SeriesKey {
+        labels.sort();
+        SeriesKey { name, labels }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn labels(&self) -> &[(String, String)] {
+        &self.labels
+    }
+}
+
+// Written the way Prometheus does, eg. `http_requests{code="200",method="GET"}`
+impl fmt::Display for SeriesKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        if self.labels.is_empty() {
+            return Ok(());
+        }
+
+        write!(f, "{{")?;
+        for (idx, (name, value)) in self.labels.iter().enumerate() {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            write!(f, "{}{}=\"{}\"", if idx > 0 { "," } else { "" }, name, value)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct InvalidSeriesKey(pub String);
+
+impl FromStr for SeriesKey {
+    type Err = InvalidSeriesKey;
+
+    fn from_str(s: &str) -> Result<SeriesKey, InvalidSeriesKey> {
+        let invalid = || InvalidSeriesKey(s.to_string());
+
+        let (name, mut rest) = match s.find('{') {
+            Some(idx) => (&s[..idx], s[idx + 1..].strip_suffix('}').ok_or_else(invalid)?),
+            None => (s, "")
+        };
+
+        if name.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut labels = Vec::new();
+
+        while !rest.is_empty() {
+            let (label, after) = rest.split_once("=\"").ok_or_else(invalid)?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+
+            rest = loop {
+                match chars.next().ok_or_else(invalid)? {
+                    (_, '\\') => match chars.next().ok_or_else(invalid)?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c)
+                    },
+                    (idx, '"') => break &after[idx + 1..],
+                    (_, c) => value.push(c)
+                }
+            };
+
+            if label.is_empty() {
+                return Err(invalid());
+            }
+
+            labels.push((label.to_string(), value));
+
+            rest = match rest.strip_prefix(',') {
+                Some(r) => r,
+                None if rest.is_empty() => rest,
+                None => return Err(invalid())
+            };
+        }
+
+        Ok(SeriesKey::new(name.to_string(), labels))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub key: SeriesKey,
+    pub measurement: Measurement
+}
+
+// Shared between a listener's threads to track how much input it accepted
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub received: AtomicU64,
+    pub malformed: AtomicU64,
+    // Connections that failed before they could be handled
+    pub accept_errors: AtomicU64
+}
+
+// How long a listener waits after a failed accept
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Counts a failed accept and pauses, so a lasting failure like running out of file
+// descriptors doesn't spin the accept loop
+fn accept_failed(counters: &Counters)
+{
+    counters.accept_errors.fetch_add(1, Ordering::Relaxed);
+    thread::sleep(ACCEPT_BACKOFF);
+}
+
+// Longest line the line based listeners will buffer, so a client can't exhaust memory by
+// never sending a newline
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+enum Line {
+    // Without the trailing newline
+    Complete(Vec<u8>),
+    // Longer than MAX_LINE_LENGTH, and skipped
+    TooLong
+}
+
+struct Lines<R> {
+    reader: R
+}
+
+impl<R: BufRead> Lines<R> {
+    // Throws away input up to and including the next newline
+    fn skip_line(&mut self) -> io::Result<()> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+
+            match buf.iter().position(|&b| b == b'\n') {
+                Some(idx) => {
+                    self.reader.consume(idx + 1);
+                    return Ok(());
+                },
+                None if buf.is_empty() => return Ok(()),
+                None => {
+                    let len = buf.len();
+                    self.reader.consume(len);
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+    type Item = io::Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+
+        match (&mut self.reader).take(MAX_LINE_LENGTH as u64 + 1).read_until(b'\n', &mut line) {
+            Ok(0) => None,
+            Err(e) => Some(Err(e)),
+            Ok(_) if line.last() == Some(&b'\n') => {
+                line.pop();
+                Some(Ok(Line::Complete(line)))
+            },
+            Ok(_) if line.len() <= MAX_LINE_LENGTH => Some(Ok(Line::Complete(line))),
+            Ok(_) => Some(self.skip_line().map(|_| Line::TooLong))
+        }
+    }
+}
+
+// Splits input on newlines like BufRead::split(), but never buffers more than
+// MAX_LINE_LENGTH bytes of a line
+fn lines<R: BufRead>(reader: R) -> Lines<R>
+{
+    Lines { reader }
+}
+
+// Adds `added` to `labels`, replacing any with the same name
+pub(crate) fn merge_labels(labels: &mut Vec<(String, String)>, added: Vec<(String, String)>)
+{
+    for (name, value) in added {
+        match labels.iter_mut().find(|(n, _)| *n == name) {
+            Some(label) => label.1 = value,
+            None => labels.push((name, value))
+        }
+    }
+}
+
+fn now_millis() -> u64
+{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines()
+    {
+        let mut input = b"a\r\n\nb".to_vec();
+        input.extend(vec![b'x'; MAX_LINE_LENGTH + 10]);
+        input.extend_from_slice(b"\nc\n");
+        input.extend(vec![b'y'; MAX_LINE_LENGTH]);
+
+        let lines: Vec<Option<Vec<u8>>> = lines(&input[..])
+            .map(|line| match line.unwrap() {
+                Line::Complete(line) => Some(line),
+                Line::TooLong => None
+            })
+            .collect();
+
+        assert_eq!(lines, vec![Some(b"a\r".to_vec()), Some(Vec::new()), None, Some(b"c".to_vec()), Some(vec![b'y'; MAX_LINE_LENGTH])]);
+    }
+
+    #[test]
+    fn test_series_key_text()
+    {
+        let key = SeriesKey::new("http_requests".to_string(),
+                                 vec![("path".to_string(), "/a,\"b\"\\c\n".to_string()), ("code".to_string(), "200".to_string())]);
+        let text = key.to_string();
+
+        assert_eq!(text, r#"http_requests{code="200",path="/a,\"b\"\\c\n"}"#);
+        assert_eq!(text.parse(), Ok(key));
+        assert_eq!("up".parse(), Ok(SeriesKey::new("up".to_string(), vec![])));
+
+        for bad in &["", "{a=\"b\"}", "up{a=\"b\"", "up{a=b}", "up{a=\"b\"c=\"d\"}", "up{=\"b\"}"] {
+            assert_eq!(bad.parse::<SeriesKey>(), Err(InvalidSeriesKey(bad.to_string())), "{}", bad);
+        }
+    }
+}
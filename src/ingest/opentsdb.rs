@@ -0,0 +1,366 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::SyncSender;
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::gorilla_tsz::Measurement;
+use super::{SeriesKey, Sample, Counters, Line, accept_failed, lines};
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseError {
+    NotEnoughArguments(usize),
+    EmptyMetric,
+    MissingTimestamp,
+    InvalidTimestamp(String),
+    MissingValue,
+    InvalidValue(String),
+    MissingTags,
+    InvalidTag(String),
+    UnknownCommand(String)
+}
+
+// Messages follow the wording OpenTSDB uses for the same errors
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::NotEnoughArguments(n) => write!(f, "not enough arguments (need at least 4, got {})", n),
+            ParseError::EmptyMetric => write!(f, "Metric name was empty"),
+            ParseError::MissingTimestamp => write!(f, "Missing timestamp"),
+            ParseError::InvalidTimestamp(ts) => write!(f, "Invalid timestamp: {}", ts),
+            ParseError::MissingValue => write!(f, "Missing value"),
+            ParseError::InvalidValue(v) => write!(f, "Unable to parse value to a number: {}", v),
+            ParseError::MissingTags => write!(f, "Missing tags"),
+            ParseError::InvalidTag(t) => write!(f, "invalid tag: {}", t),
+            ParseError::UnknownCommand(c) => write!(f, "unknown command: {}", c)
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+// Timestamps are seconds unless they don't fit in 32 bits, in which case they're taken
+// as milliseconds, the same rule OpenTSDB applies. Fractional seconds are also accepted.
+fn parse_timestamp(ts: &str) -> Result<u64, ParseError>
+{
+    let invalid = || ParseError::InvalidTimestamp(ts.to_string());
+
+    if let Some((secs, frac)) = ts.split_once('.') {
+        if frac.is_empty() || frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        let secs = secs.parse::<u32>().map_err(|_| invalid())? as u64;
+        let millis = frac.parse::<u64>().map_err(|_| invalid())? * 10u64.pow(3 - frac.len() as u32);
+
+        return Ok(secs * 1000 + millis);
+    }
+
+    match ts.parse::<u64>() {
+        Ok(ts) if ts <= u32::MAX as u64 => Ok(ts * 1000),
+        // 13 digits is the most OpenTSDB accepts for milliseconds
+        Ok(ts) if ts <= 9_999_999_999_999 => Ok(ts),
+        _ => Err(invalid())
+    }
+}
+
+fn parse_value(value: &str) -> Result<f64, ParseError>
+{
+    match value.parse::<f64>() {
+        Ok(v) if v.is_finite() => Ok(v),
+        _ => Err(ParseError::InvalidValue(value.to_string()))
+    }
+}
+
+fn sample(metric: &str, timestamp: u64, value: f64, tags: Vec<(String, String)>) -> Result<Sample, ParseError>
+{
+    if metric.is_empty() {
+        return Err(ParseError::EmptyMetric);
+    }
+
+    if tags.is_empty() {
+        return Err(ParseError::MissingTags);
+    }
+
+    Ok(Sample {
+        key: SeriesKey::new(metric.to_string(), tags),
+        measurement: Measurement { timestamp, count: 1, value }
+    })
+}
+
+// Parses the arguments of a telnet `put <metric> <timestamp> <value> <tagk=tagv> ...` line
+pub fn parse_put(line: &str) -> Result<Sample, ParseError>
+{
+    let args: Vec<&str> = line.split_whitespace().collect();
+
+    if let Some(&command) = args.first().filter(|&&command| command != "put") {
+        return Err(ParseError::UnknownCommand(command.to_string()));
+    }
+
+    // The `put` itself doesn't count
+    if args.len() < 5 {
+        return Err(ParseError::NotEnoughArguments(args.len().saturating_sub(1)));
+    }
+
+    let mut tags = Vec::new();
+    for tag in &args[4..] {
+        match tag.split_once('=') {
+            Some((k, v)) if !k.is_empty() && !v.is_empty() => tags.push((k.to_string(), v.to_string())),
+            _ => return Err(ParseError::InvalidTag(tag.to_string()))
+        }
+    }
+
+    sample(args[1], parse_timestamp(args[2])?, parse_value(args[3])?, tags)
+}
+
+fn parse_datapoint(dp: &Value) -> Result<Sample, ParseError>
+{
+    let metric = dp.get("metric").and_then(Value::as_str).unwrap_or("");
+
+    let timestamp = match dp.get("timestamp") {
+        None | Some(Value::Null) => return Err(ParseError::MissingTimestamp),
+        Some(Value::String(ts)) => parse_timestamp(ts)?,
+        Some(ts) => parse_timestamp(&ts.to_string())?
+    };
+
+    // Values may be sent as numbers or strings
+    let value = match dp.get("value") {
+        None | Some(Value::Null) => return Err(ParseError::MissingValue),
+        Some(Value::String(v)) => parse_value(v)?,
+        Some(Value::Number(v)) => v.as_f64().ok_or_else(|| ParseError::InvalidValue(v.to_string()))?,
+        Some(v) => return Err(ParseError::InvalidValue(v.to_string()))
+    };
+
+    let mut tags = Vec::new();
+    if let Some(map) = dp.get("tags").and_then(Value::as_object) {
+        for (k, v) in map {
+            match v.as_str() {
+                Some(v) if !k.is_empty() && !v.is_empty() => tags.push((k.clone(), v.to_string())),
+                _ => return Err(ParseError::InvalidTag(format!("{}={}", k, v)))
+            }
+        }
+    }
+
+    sample(metric, timestamp, value, tags)
+}
+
+pub struct PutResponse {
+    pub samples: Vec<Sample>,
+    pub status: u16,
+    // None for a 204
+    pub body: Option<String>
+}
+
+fn error_response(message: &str, details: &str) -> PutResponse
+{
+    let body = json!({"error": {"code": 400, "message": message, "details": details}});
+
+    PutResponse { samples: Vec::new(), status: 400, body: Some(body.to_string()) }
+}
+
+// Handles an HTTP /api/put body: a single data point object or an array of them. Valid
+// data points are returned for storage even if others fail, and the response mirrors
+// OpenTSDB's, including the `summary` and `details` query parameter variants.
+pub fn api_put(body: &[u8], summary: bool, details: bool) -> PutResponse
+{
+    let datapoints = match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(dps)) => dps,
+        Ok(dp @ Value::Object(_)) => vec![dp],
+        _ => return error_response("Unable to parse the given JSON", "")
+    };
+
+    let mut samples = Vec::new();
+    let mut errors = Vec::new();
+
+    for dp in datapoints {
+        match parse_datapoint(&dp) {
+            Ok(sample) => samples.push(sample),
+            Err(e) => errors.push(json!({"datapoint": dp, "error": e.to_string()}))
+        }
+    }
+
+    let status = if errors.is_empty() { 204 } else { 400 };
+    let counts = json!({"failed": errors.len(), "success": samples.len()});
+
+    let body = if details {
+        let mut body = counts;
+        body["errors"] = Value::Array(errors);
+        Some(body.to_string())
+    } else if summary {
+        Some(counts.to_string())
+    } else if status == 400 {
+        return PutResponse {
+            samples,
+            ..error_response("One or more data points had errors",
+                             "Please see the TSD logs or append \"details\" to the put request")
+        };
+    } else {
+        None
+    };
+
+    PutResponse { samples, status: if body.is_some() && status == 204 { 200 } else { status }, body }
+}
+
+// Serves one telnet style client until EOF. Valid `put`s are sent to `sink` without a reply
+// and bad ones are answered with OpenTSDB's error line. Lines too long to buffer are
+// counted as malformed and dropped.
+pub fn handle_connection<R: BufRead, W: Write>(reader: R, mut writer: W, sink: &SyncSender<Sample>,
+                                               counters: &Counters) -> io::Result<()>
+{
+    for line in lines(reader) {
+        let line = match line? {
+            Line::Complete(line) => line,
+            Line::TooLong => {
+                counters.malformed.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim();
+
+        let reply = match line.split_whitespace().next() {
+            None => continue,
+            Some("put") => match parse_put(line) {
+                Ok(sample) => {
+                    counters.received.fetch_add(1, Ordering::Relaxed);
+
+                    if sink.send(sample).is_err() {
+                        return Err(io::Error::new(io::ErrorKind::BrokenPipe, "sample receiver closed"));
+                    }
+                    continue;
+                },
+                Err(e) => {
+                    counters.malformed.fetch_add(1, Ordering::Relaxed);
+                    format!("put: illegal argument: {}\n", e)
+                }
+            },
+            Some("exit") => return Ok(()),
+            Some(command) => format!("unknown command: {}.  Try `help'.\n", command)
+        };
+
+        writer.write_all(reply.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+// Accepts telnet style connections forever, handling each on its own thread. A connection
+// that fails to set up is dropped without stopping the listener, and is counted in
+// accept_errors.
+pub fn serve(listener: TcpListener, sink: SyncSender<Sample>, counters: Arc<Counters>) -> io::Result<()>
+{
+    for stream in listener.incoming() {
+        let (stream, writer) = match stream.and_then(|stream| stream.try_clone().map(|writer| (stream, writer))) {
+            Ok(pair) => pair,
+            Err(_) => {
+                accept_failed(&counters);
+                continue;
+            }
+        };
+        let sink = sink.clone();
+        let counters = counters.clone();
+
+        thread::spawn(move || {
+            let _ = handle_connection(BufReader::new(stream), writer, &sink, &counters);
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::mpsc::sync_channel;
+    use super::super::testing::key;
+
+    #[test]
+    fn test_parse_put()
+    {
+        let sample = parse_put("put sys.cpu.user 1356998400 42.5 host=web01 cpu=0").unwrap();
+        assert_eq!(sample.key, key("sys.cpu.user", &[("host", "web01"), ("cpu", "0")]));
+        assert_eq!(sample.measurement, Measurement{timestamp: 1356998400000, count: 1, value: 42.5});
+
+        assert_eq!(parse_put("put m 1356998400123 1 a=b").unwrap().measurement.timestamp, 1356998400123);
+        assert_eq!(parse_put("put m 1356998400.5 1 a=b").unwrap().measurement.timestamp, 1356998400500);
+        assert_eq!(parse_put("put m 4294967295 1 a=b").unwrap().measurement.timestamp, 4294967295000);
+
+        assert_eq!(parse_put("put m 1 1"), Err(ParseError::NotEnoughArguments(3)));
+        assert_eq!(parse_put(""), Err(ParseError::NotEnoughArguments(0)));
+        assert_eq!(parse_put("get m 1 1 a=b"), Err(ParseError::UnknownCommand("get".to_string())));
+        assert_eq!(ParseError::NotEnoughArguments(3).to_string(), "not enough arguments (need at least 4, got 3)");
+        assert_eq!(parse_put("put m 1 1 host"), Err(ParseError::InvalidTag("host".to_string())));
+        assert_eq!(parse_put("put m 99999999999999 1 a=b"), Err(ParseError::InvalidTimestamp("99999999999999".to_string())));
+        assert_eq!(parse_put("put m 1.5555 1 a=b"), Err(ParseError::InvalidTimestamp("1.5555".to_string())));
+        assert_eq!(parse_put("put m 1 NaN a=b"), Err(ParseError::InvalidValue("NaN".to_string())));
+    }
+
+    #[test]
+    fn test_api_put()
+    {
+        let body = br#"[{"metric": "sys.cpu.nice", "timestamp": 1346846400, "value": 18, "tags": {"host": "web01"}},
+                        {"metric": "sys.cpu.nice", "timestamp": 1346846400500, "value": "9.5", "tags": {"host": "web02"}}]"#;
+
+        let response = api_put(body, false, false);
+        assert_eq!(response.status, 204);
+        assert_eq!(response.body, None);
+        assert_eq!(response.samples.iter().map(|s| (s.measurement.timestamp, s.measurement.value)).collect::<Vec<_>>(),
+                   vec![(1346846400000, 18.0), (1346846400500, 9.5)]);
+
+        let single = br#"{"metric": "m", "timestamp": 1, "value": 1, "tags": {"a": "b"}}"#;
+        let response = api_put(single, true, false);
+        assert_eq!((response.status, response.body), (200, Some(r#"{"failed":0,"success":1}"#.to_string())));
+    }
+
+    #[test]
+    fn test_api_put_errors()
+    {
+        let body = br#"[{"metric": "m", "timestamp": 1, "value": 1, "tags": {"a": "b"}},
+                        {"metric": "m", "timestamp": 1, "value": 1}]"#;
+
+        let response = api_put(body, false, true);
+        assert_eq!(response.status, 400);
+        assert_eq!(response.samples.len(), 1);
+
+        let errors: Value = serde_json::from_str(&response.body.unwrap()).unwrap();
+        assert_eq!(errors["failed"], 1);
+        assert_eq!(errors["success"], 1);
+        assert_eq!(errors["errors"][0]["error"], "Missing tags");
+        assert_eq!(errors["errors"][0]["datapoint"]["metric"], "m");
+
+        let response = api_put(body, false, false);
+        let error: Value = serde_json::from_str(&response.body.unwrap()).unwrap();
+        assert_eq!(response.status, 400);
+        assert_eq!(error["error"]["message"], "One or more data points had errors");
+
+        let response = api_put(b"{nope", false, false);
+        let error: Value = serde_json::from_str(&response.body.unwrap()).unwrap();
+        assert_eq!(error["error"]["message"], "Unable to parse the given JSON");
+    }
+
+    #[test]
+    fn test_handle_connection()
+    {
+        let (tx, rx) = sync_channel(16);
+        let counters = Counters::default();
+        let mut output = Vec::new();
+        let long = format!("put z 1 1 x={}", "y".repeat(100 * 1024));
+        let input = format!("put a 1 1 x=y\nput a\n{}\nversion\n\nput b 2 2 x=y\nexit\nput c 3 3 x=y\n", long);
+
+        handle_connection(Cursor::new(input), &mut output, &tx, &counters).unwrap();
+        drop(tx);
+
+        assert_eq!(rx.iter().map(|s| s.key.name().to_string()).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "put: illegal argument: not enough arguments (need at least 4, got 1)\n\
+                    unknown command: version.  Try `help'.\n");
+        assert_eq!(counters.malformed.load(Ordering::Relaxed), 2);
+    }
+}
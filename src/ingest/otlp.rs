@@ -0,0 +1,720 @@
+// Decoding of OTLP/HTTP metrics export requests, in either the protobuf or the JSON encoding.
+// See https://opentelemetry.io/docs/specs/otlp/ and opentelemetry/proto/metrics/v1.
+//
+// Resource attributes, the instrumentation scope (as otel_scope_name/otel_scope_version plus
+// its attributes) and data point attributes are merged into the labels, later ones winning.
+// Sums and histograms are labelled with their otel_temporality: cumulative points can be
+// queried with Rate/Increase, delta points summed. Histograms are flattened the way Prometheus
+// does it, into <name>_bucket series with a cumulative `le` label plus <name>_sum and
+// <name>_count. Exponential histograms and summaries are skipped.
+
+use std::error;
+use std::fmt;
+
+use serde_json::Value as Json;
+
+use crate::gorilla_tsz::Measurement;
+use super::{SeriesKey, Sample, merge_labels};
+use super::protobuf::{Reader, ProtobufError, Value};
+use super::remote_write::STALE_NAN;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum OtlpError {
+    ProtobufError(ProtobufError),
+    InvalidJson(String),
+    MissingMetricName
+}
+
+impl fmt::Display for OtlpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OtlpError::ProtobufError(e) => write!(f, "protobuf: {}", e),
+            OtlpError::InvalidJson(e) => write!(f, "invalid JSON: {}", e),
+            OtlpError::MissingMetricName => write!(f, "metric has no name")
+        }
+    }
+}
+
+impl error::Error for OtlpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            OtlpError::ProtobufError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<ProtobufError> for OtlpError {
+    fn from(e: ProtobufError) -> Self {
+        OtlpError::ProtobufError(e)
+    }
+}
+
+// Data point flag marking that no value was recorded, which we store as a staleness marker
+const FLAG_NO_RECORDED_VALUE: u64 = 1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Temporality {
+    Unspecified,
+    Delta,
+    Cumulative
+}
+
+impl Temporality {
+    fn from_i64(value: i64) -> Temporality {
+        match value {
+            1 => Temporality::Delta,
+            2 => Temporality::Cumulative,
+            _ => Temporality::Unspecified
+        }
+    }
+
+    fn label(self) -> Option<&'static str> {
+        match self {
+            Temporality::Unspecified => None,
+            Temporality::Delta => Some("delta"),
+            Temporality::Cumulative => Some("cumulative")
+        }
+    }
+}
+
+type Labels = Vec<(String, String)>;
+
+struct NumberPoint {
+    attributes: Labels,
+    time_unix_nano: u64,
+    value: f64,
+    flags: u64
+}
+
+struct HistogramPoint {
+    attributes: Labels,
+    time_unix_nano: u64,
+    count: u64,
+    sum: Option<f64>,
+    bucket_counts: Vec<u64>,
+    explicit_bounds: Vec<f64>,
+    flags: u64
+}
+
+enum Data {
+    Gauge(Vec<NumberPoint>),
+    Sum(Vec<NumberPoint>, Temporality),
+    Histogram(Vec<HistogramPoint>, Temporality),
+    Unsupported
+}
+
+fn push_sample(samples: &mut Vec<Sample>, name: String, labels: Labels, time_unix_nano: u64, value: f64)
+{
+    samples.push(Sample {
+        key: SeriesKey::new(name, labels),
+        measurement: Measurement { timestamp: time_unix_nano / 1_000_000, count: 1, value }
+    });
+}
+
+fn flatten(name: &str, mut labels: Labels, data: Data, samples: &mut Vec<Sample>)
+{
+    let temporality = match data {
+        Data::Sum(_, t) | Data::Histogram(_, t) => t.label(),
+        _ => None
+    };
+
+    if let Some(temporality) = temporality {
+        merge_labels(&mut labels, vec![("otel_temporality".to_string(), temporality.to_string())]);
+    }
+
+    match data {
+        Data::Gauge(points) | Data::Sum(points, _) => {
+            for point in points {
+                let mut point_labels = labels.clone();
+                merge_labels(&mut point_labels, point.attributes);
+
+                let value = if point.flags & FLAG_NO_RECORDED_VALUE != 0 { f64::from_bits(STALE_NAN) } else { point.value };
+                push_sample(samples, name.to_string(), point_labels, point.time_unix_nano, value);
+            }
+        },
+        Data::Histogram(points, _) => {
+            for point in points {
+                let mut point_labels = labels.clone();
+                merge_labels(&mut point_labels, point.attributes);
+
+                let stale = point.flags & FLAG_NO_RECORDED_VALUE != 0;
+                let value = |v: f64| if stale { f64::from_bits(STALE_NAN) } else { v };
+
+                // OTLP bucket counts are per bucket, `le` buckets include all lower ones
+                let mut cumulative = 0u64;
+                for (idx, count) in point.bucket_counts.iter().enumerate() {
+                    cumulative = cumulative.wrapping_add(*count);
+
+                    let le = match point.explicit_bounds.get(idx) {
+                        Some(bound) => bound.to_string(),
+                        None => "+Inf".to_string()
+                    };
+
+                    let mut bucket_labels = point_labels.clone();
+                    merge_labels(&mut bucket_labels, vec![("le".to_string(), le)]);
+                    push_sample(samples, format!("{}_bucket", name), bucket_labels, point.time_unix_nano, value(cumulative as f64));
+                }
+
+                if let Some(sum) = point.sum {
+                    push_sample(samples, format!("{}_sum", name), point_labels.clone(), point.time_unix_nano, value(sum));
+                }
+                push_sample(samples, format!("{}_count", name), point_labels, point.time_unix_nano, value(point.count as f64));
+            }
+        },
+        Data::Unsupported => {}
+    }
+}
+
+// message KeyValue { string key = 1; AnyValue value = 2; }
+//
+// Only scalar values are kept, arrays, key/value lists and bytes are dropped.
+fn decode_attribute(buf: &[u8], labels: &mut Labels) -> Result<(), OtlpError>
+{
+    let mut reader = Reader::new(buf);
+    let mut key = "";
+    let mut value = None;
+
+    while let Some((field, v)) = reader.next_field()? {
+        match field {
+            1 => key = v.as_str()?,
+            2 => {
+                let mut any = Reader::new(v.as_bytes()?);
+                while let Some((field, v)) = any.next_field()? {
+                    value = match field {
+                        1 => Some(v.as_str()?.to_string()),
+                        2 => Some((v.as_varint()? != 0).to_string()),
+                        3 => Some((v.as_varint()? as i64).to_string()),
+                        4 => Some(v.as_f64()?.to_string()),
+                        _ => None
+                    };
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // A repeated key keeps its last value, as with the other attribute levels
+    if let Some(value) = value {
+        merge_labels(labels, vec![(key.to_string(), value)]);
+    }
+    Ok(())
+}
+
+// message Resource { repeated KeyValue attributes = 1; ... }
+fn decode_resource(buf: &[u8]) -> Result<Labels, OtlpError>
+{
+    let mut reader = Reader::new(buf);
+    let mut labels = Vec::new();
+
+    while let Some((field, v)) = reader.next_field()? {
+        if field == 1 {
+            decode_attribute(v.as_bytes()?, &mut labels)?;
+        }
+    }
+
+    Ok(labels)
+}
+
+// message InstrumentationScope { string name = 1; string version = 2; repeated KeyValue attributes = 3; ... }
+fn decode_scope(buf: &[u8]) -> Result<Labels, OtlpError>
+{
+    let mut reader = Reader::new(buf);
+    let mut labels = Vec::new();
+
+    while let Some((field, v)) = reader.next_field()? {
+        match field {
+            1 => labels.push(("otel_scope_name".to_string(), v.as_str()?.to_string())),
+            2 => labels.push(("otel_scope_version".to_string(), v.as_str()?.to_string())),
+            3 => decode_attribute(v.as_bytes()?, &mut labels)?,
+            _ => {}
+        }
+    }
+
+    labels.retain(|(_, value)| !value.is_empty());
+    Ok(labels)
+}
+
+// message NumberDataPoint { fixed64 time_unix_nano = 3; double as_double = 4; sfixed64 as_int = 6;
+//                           repeated KeyValue attributes = 7; uint32 flags = 8; ... }
+fn decode_number_point(buf: &[u8]) -> Result<NumberPoint, OtlpError>
+{
+    let mut reader = Reader::new(buf);
+    let mut point = NumberPoint { attributes: Vec::new(), time_unix_nano: 0, value: 0.0, flags: 0 };
+
+    while let Some((field, v)) = reader.next_field()? {
+        match field {
+            3 => point.time_unix_nano = v.as_fixed64()?,
+            4 => point.value = v.as_f64()?,
+            6 => point.value = v.as_fixed64()? as i64 as f64,
+            7 => decode_attribute(v.as_bytes()?, &mut point.attributes)?,
+            8 => point.flags = v.as_varint()?,
+            _ => {}
+        }
+    }
+
+    Ok(point)
+}
+
+// Repeated fixed64/double fields may be packed into one length delimited field or not
+fn decode_repeated_fixed64(v: Value, values: &mut Vec<u64>) -> Result<(), OtlpError>
+{
+    match v {
+        Value::Bytes(bytes) if bytes.len() % 8 == 0 => {
+            values.extend(bytes.chunks(8).map(|b| u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])));
+        },
+        Value::Bytes(_) => return Err(OtlpError::ProtobufError(ProtobufError::Truncated)),
+        v => values.push(v.as_fixed64()?)
+    }
+    Ok(())
+}
+
+// message HistogramDataPoint { fixed64 time_unix_nano = 3; fixed64 count = 4; optional double sum = 5;
+//                              repeated fixed64 bucket_counts = 6; repeated double explicit_bounds = 7;
+//                              repeated KeyValue attributes = 9; uint32 flags = 10; ... }
+fn decode_histogram_point(buf: &[u8]) -> Result<HistogramPoint, OtlpError>
+{
+    let mut reader = Reader::new(buf);
+    let mut bounds = Vec::new();
+    let mut point = HistogramPoint { attributes: Vec::new(), time_unix_nano: 0, count: 0, sum: None,
+                                     bucket_counts: Vec::new(), explicit_bounds: Vec::new(), flags: 0 };
+
+    while let Some((field, v)) = reader.next_field()? {
+        match field {
+            3 => point.time_unix_nano = v.as_fixed64()?,
+            4 => point.count = v.as_fixed64()?,
+            5 => point.sum = Some(v.as_f64()?),
+            6 => decode_repeated_fixed64(v, &mut point.bucket_counts)?,
+            7 => decode_repeated_fixed64(v, &mut bounds)?,
+            9 => decode_attribute(v.as_bytes()?, &mut point.attributes)?,
+            10 => point.flags = v.as_varint()?,
+            _ => {}
+        }
+    }
+
+    point.explicit_bounds = bounds.into_iter().map(f64::from_bits).collect();
+    Ok(point)
+}
+
+// message Gauge { repeated NumberDataPoint data_points = 1; }
+// message Sum { repeated NumberDataPoint data_points = 1; AggregationTemporality aggregation_temporality = 2; ... }
+// message Histogram { repeated HistogramDataPoint data_points = 1; AggregationTemporality aggregation_temporality = 2; }
+fn decode_data(buf: &[u8], metric_field: u64) -> Result<Data, OtlpError>
+{
+    let mut reader = Reader::new(buf);
+    let mut number_points = Vec::new();
+    let mut histogram_points = Vec::new();
+    let mut temporality = Temporality::Unspecified;
+
+    while let Some((field, v)) = reader.next_field()? {
+        match (metric_field, field) {
+            (5, 1) | (7, 1) => number_points.push(decode_number_point(v.as_bytes()?)?),
+            (9, 1) => histogram_points.push(decode_histogram_point(v.as_bytes()?)?),
+            (7, 2) | (9, 2) => temporality = Temporality::from_i64(v.as_varint()? as i64),
+            _ => {}
+        }
+    }
+
+    Ok(match metric_field {
+        5 => Data::Gauge(number_points),
+        7 => Data::Sum(number_points, temporality),
+        _ => Data::Histogram(histogram_points, temporality)
+    })
+}
+
+// message Metric { string name = 1; ... Gauge gauge = 5; Sum sum = 7; Histogram histogram = 9; ... }
+fn decode_metric(buf: &[u8], labels: &Labels, samples: &mut Vec<Sample>) -> Result<(), OtlpError>
+{
+    let mut reader = Reader::new(buf);
+    let mut name = "";
+    let mut data = Data::Unsupported;
+
+    while let Some((field, v)) = reader.next_field()? {
+        match field {
+            1 => name = v.as_str()?,
+            5 | 7 | 9 => data = decode_data(v.as_bytes()?, field)?,
+            _ => {}
+        }
+    }
+
+    if name.is_empty() {
+        return Err(OtlpError::MissingMetricName);
+    }
+
+    flatten(name, labels.clone(), data, samples);
+    Ok(())
+}
+
+// message ScopeMetrics { InstrumentationScope scope = 1; repeated Metric metrics = 2; ... }
+fn decode_scope_metrics(buf: &[u8], resource: &Labels, samples: &mut Vec<Sample>) -> Result<(), OtlpError>
+{
+    // The scope may come after its metrics, so those are collected first
+    let mut reader = Reader::new(buf);
+    let mut labels = resource.clone();
+    let mut metrics = Vec::new();
+
+    while let Some((field, v)) = reader.next_field()? {
+        match field {
+            1 => merge_labels(&mut labels, decode_scope(v.as_bytes()?)?),
+            2 => metrics.push(v.as_bytes()?),
+            _ => {}
+        }
+    }
+
+    for metric in metrics {
+        decode_metric(metric, &labels, samples)?;
+    }
+    Ok(())
+}
+
+// message ResourceMetrics { Resource resource = 1; repeated ScopeMetrics scope_metrics = 2; ... }
+fn decode_resource_metrics(buf: &[u8], samples: &mut Vec<Sample>) -> Result<(), OtlpError>
+{
+    let mut reader = Reader::new(buf);
+    let mut resource = Vec::new();
+    let mut scopes = Vec::new();
+
+    while let Some((field, v)) = reader.next_field()? {
+        match field {
+            1 => resource = decode_resource(v.as_bytes()?)?,
+            2 => scopes.push(v.as_bytes()?),
+            _ => {}
+        }
+    }
+
+    for scope in scopes {
+        decode_scope_metrics(scope, &resource, samples)?;
+    }
+    Ok(())
+}
+
+// Decodes a protobuf ExportMetricsServiceRequest, as POSTed to /v1/metrics with a
+// Content-Type of application/x-protobuf
+pub fn decode(body: &[u8]) -> Result<Vec<Sample>, OtlpError>
+{
+    let mut reader = Reader::new(body);
+    let mut samples = Vec::new();
+
+    // message ExportMetricsServiceRequest { repeated ResourceMetrics resource_metrics = 1; }
+    while let Some((field, v)) = reader.next_field()? {
+        if field == 1 {
+            decode_resource_metrics(v.as_bytes()?, &mut samples)?;
+        }
+    }
+
+    Ok(samples)
+}
+
+// The JSON encoding uses the protobuf field names in lowerCamelCase, writes 64 bit integers
+// as strings and enums as their numbers.
+
+fn json_array<'a>(value: &'a Json, field: &str) -> Result<&'a [Json], OtlpError>
+{
+    match value.get(field) {
+        None | Some(Json::Null) => Ok(&[]),
+        Some(Json::Array(values)) => Ok(values),
+        Some(_) => Err(OtlpError::InvalidJson(format!("{} must be an array", field)))
+    }
+}
+
+fn json_u64(value: &Json, field: &str) -> Result<u64, OtlpError>
+{
+    let invalid = || OtlpError::InvalidJson(format!("{} must be an unsigned integer", field));
+
+    match value {
+        Json::String(s) => s.parse().map_err(|_| invalid()),
+        v => v.as_u64().ok_or_else(invalid)
+    }
+}
+
+// A missing field has the protobuf default of 0
+fn json_u64_field(object: &Json, field: &str) -> Result<u64, OtlpError>
+{
+    match object.get(field) {
+        None | Some(Json::Null) => Ok(0),
+        Some(value) => json_u64(value, field)
+    }
+}
+
+fn json_i64(value: &Json, field: &str) -> Result<i64, OtlpError>
+{
+    let invalid = || OtlpError::InvalidJson(format!("{} must be an integer", field));
+
+    match value {
+        Json::String(s) => s.parse().map_err(|_| invalid()),
+        v => v.as_i64().ok_or_else(invalid)
+    }
+}
+
+// Doubles are numbers, except for the non-finite ones which are written as strings
+fn json_f64(value: &Json, field: &str) -> Result<f64, OtlpError>
+{
+    let invalid = || OtlpError::InvalidJson(format!("{} must be a number", field));
+
+    match value {
+        Json::String(s) => match s.as_str() {
+            "NaN" => Ok(f64::NAN),
+            "Infinity" => Ok(f64::INFINITY),
+            "-Infinity" => Ok(f64::NEG_INFINITY),
+            _ => Err(invalid())
+        },
+        v => v.as_f64().ok_or_else(invalid)
+    }
+}
+
+fn json_attributes(value: &Json, field: &str) -> Result<Labels, OtlpError>
+{
+    let mut labels = Vec::new();
+
+    for attribute in json_array(value, field)? {
+        let key = attribute.get("key").and_then(Json::as_str).unwrap_or("");
+        let any = attribute.get("value").unwrap_or(&Json::Null);
+
+        let value = if let Some(s) = any.get("stringValue").and_then(Json::as_str) {
+            Some(s.to_string())
+        } else if let Some(b) = any.get("boolValue").and_then(Json::as_bool) {
+            Some(b.to_string())
+        } else if let Some(i) = any.get("intValue") {
+            Some(json_i64(i, "intValue")?.to_string())
+        } else if let Some(d) = any.get("doubleValue") {
+            Some(json_f64(d, "doubleValue")?.to_string())
+        } else {
+            None
+        };
+
+        if let Some(value) = value {
+            merge_labels(&mut labels, vec![(key.to_string(), value)]);
+        }
+    }
+
+    Ok(labels)
+}
+
+fn json_number_point(point: &Json) -> Result<NumberPoint, OtlpError>
+{
+    let value = if let Some(d) = point.get("asDouble") {
+        json_f64(d, "asDouble")?
+    } else if let Some(i) = point.get("asInt") {
+        json_i64(i, "asInt")? as f64
+    } else {
+        0.0
+    };
+
+    Ok(NumberPoint {
+        attributes: json_attributes(point, "attributes")?,
+        time_unix_nano: json_u64_field(point, "timeUnixNano")?,
+        value,
+        flags: json_u64_field(point, "flags")?
+    })
+}
+
+fn json_histogram_point(point: &Json) -> Result<HistogramPoint, OtlpError>
+{
+    let bucket_counts = json_array(point, "bucketCounts")?.iter()
+        .map(|c| json_u64(c, "bucketCounts"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let explicit_bounds = json_array(point, "explicitBounds")?.iter()
+        .map(|b| json_f64(b, "explicitBounds"))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(HistogramPoint {
+        attributes: json_attributes(point, "attributes")?,
+        time_unix_nano: json_u64_field(point, "timeUnixNano")?,
+        count: json_u64_field(point, "count")?,
+        sum: point.get("sum").map(|s| json_f64(s, "sum")).transpose()?,
+        bucket_counts,
+        explicit_bounds,
+        flags: json_u64_field(point, "flags")?
+    })
+}
+
+fn json_metric(metric: &Json, labels: &Labels, samples: &mut Vec<Sample>) -> Result<(), OtlpError>
+{
+    let name = metric.get("name").and_then(Json::as_str).unwrap_or("");
+
+    if name.is_empty() {
+        return Err(OtlpError::MissingMetricName);
+    }
+
+    let temporality = |data: &Json| data.get("aggregationTemporality")
+        .map_or(Ok(0), |t| json_i64(t, "aggregationTemporality"))
+        .map(Temporality::from_i64);
+
+    let data = if let Some(gauge) = metric.get("gauge") {
+        Data::Gauge(json_array(gauge, "dataPoints")?.iter().map(json_number_point).collect::<Result<_, _>>()?)
+    } else if let Some(sum) = metric.get("sum") {
+        Data::Sum(json_array(sum, "dataPoints")?.iter().map(json_number_point).collect::<Result<_, _>>()?,
+                  temporality(sum)?)
+    } else if let Some(histogram) = metric.get("histogram") {
+        Data::Histogram(json_array(histogram, "dataPoints")?.iter().map(json_histogram_point).collect::<Result<_, _>>()?,
+                        temporality(histogram)?)
+    } else {
+        Data::Unsupported
+    };
+
+    flatten(name, labels.clone(), data, samples);
+    Ok(())
+}
+
+// Decodes a JSON ExportMetricsServiceRequest, as POSTed to /v1/metrics with a Content-Type
+// of application/json
+pub fn decode_json(body: &[u8]) -> Result<Vec<Sample>, OtlpError>
+{
+    let request: Json = serde_json::from_slice(body).map_err(|e| OtlpError::InvalidJson(e.to_string()))?;
+    let mut samples = Vec::new();
+
+    for resource_metrics in json_array(&request, "resourceMetrics")? {
+        let resource = json_attributes(resource_metrics.get("resource").unwrap_or(&Json::Null), "attributes")?;
+
+        for scope_metrics in json_array(resource_metrics, "scopeMetrics")? {
+            let mut labels = resource.clone();
+
+            if let Some(scope) = scope_metrics.get("scope") {
+                let mut scope_labels = Vec::new();
+                for (field, label) in &[("name", "otel_scope_name"), ("version", "otel_scope_version")] {
+                    match scope.get(*field).and_then(Json::as_str) {
+                        Some(value) if !value.is_empty() => scope_labels.push((label.to_string(), value.to_string())),
+                        _ => {}
+                    }
+                }
+                scope_labels.extend(json_attributes(scope, "attributes")?);
+                merge_labels(&mut labels, scope_labels);
+            }
+
+            for metric in json_array(scope_metrics, "metrics")? {
+                json_metric(metric, &labels, &mut samples)?;
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::{bytes_field, fixed64_field, labels, message, varint_field};
+
+    fn string_attribute(key: &str, value: &str) -> Vec<u8> {
+        message(|kv| {
+            bytes_field(kv, 1, key.as_bytes());
+            bytes_field(kv, 2, &message(|any| bytes_field(any, 1, value.as_bytes())));
+        })
+    }
+
+    #[test]
+    fn test_decode()
+    {
+        let gauge_point = message(|p| {
+            fixed64_field(p, 3, 1_567_029_708_123_000_000);
+            fixed64_field(p, 4, 21.5f64.to_bits());
+            bytes_field(p, 7, &string_attribute("host", "web01"));
+        });
+        let sum_point = message(|p| {
+            fixed64_field(p, 3, 1_567_029_708_000_000_000);
+            fixed64_field(p, 6, 42);
+            varint_field(p, 8, FLAG_NO_RECORDED_VALUE);
+        });
+        let histogram_point = message(|p| {
+            fixed64_field(p, 3, 1_567_029_708_000_000_000);
+            fixed64_field(p, 4, 6);
+            fixed64_field(p, 5, 7.5f64.to_bits());
+            bytes_field(p, 6, &[1u64, 2, 3].iter().flat_map(|c| c.to_le_bytes()).collect::<Vec<u8>>());
+            fixed64_field(p, 7, 0.5f64.to_bits());
+            fixed64_field(p, 7, 1.0f64.to_bits());
+        });
+
+        let metrics = [
+            message(|m| {
+                bytes_field(m, 1, b"temperature");
+                bytes_field(m, 5, &message(|g| bytes_field(g, 1, &gauge_point)));
+            }),
+            message(|m| {
+                bytes_field(m, 1, b"requests");
+                bytes_field(m, 7, &message(|s| { bytes_field(s, 1, &sum_point); varint_field(s, 2, 2); }));
+            }),
+            message(|m| {
+                bytes_field(m, 1, b"latency");
+                bytes_field(m, 9, &message(|h| { bytes_field(h, 1, &histogram_point); varint_field(h, 2, 1); }));
+            })
+        ];
+
+        let request = message(|r| bytes_field(r, 1, &message(|rm| {
+            // A repeated key keeps its last value
+            bytes_field(rm, 1, &message(|res| {
+                bytes_field(res, 1, &string_attribute("service.name", "old"));
+                bytes_field(res, 1, &string_attribute("service.name", "api"));
+            }));
+            bytes_field(rm, 2, &message(|sm| {
+                for metric in &metrics {
+                    bytes_field(sm, 2, metric);
+                }
+                bytes_field(sm, 1, &message(|scope| bytes_field(scope, 1, b"io.example")));
+            }));
+        })));
+
+        let samples = decode(&request).unwrap();
+        let base = [("service.name", "api"), ("otel_scope_name", "io.example")];
+
+        assert_eq!(samples[0].key, SeriesKey::new("temperature".to_string(), labels(&[base[0], base[1], ("host", "web01")])));
+        assert_eq!(samples[0].measurement, Measurement{timestamp: 1567029708123, count: 1, value: 21.5});
+
+        assert_eq!(samples[1].key, SeriesKey::new("requests".to_string(), labels(&[base[0], base[1], ("otel_temporality", "cumulative")])));
+        assert_eq!(samples[1].measurement.value.to_bits(), STALE_NAN);
+
+        let histogram: Vec<_> = samples[2..].iter()
+            .map(|s| (s.key.name(), s.key.labels().iter().find(|(n, _)| n == "le").map(|(_, v)| v.as_str()), s.measurement.value))
+            .collect();
+        assert_eq!(histogram, vec![("latency_bucket", Some("0.5"), 1.0), ("latency_bucket", Some("1"), 3.0),
+                                   ("latency_bucket", Some("+Inf"), 6.0), ("latency_sum", None, 7.5), ("latency_count", None, 6.0)]);
+        assert!(samples[2].key.labels().contains(&("otel_temporality".to_string(), "delta".to_string())));
+
+        assert_eq!(decode(&message(|r| bytes_field(r, 1, &message(|rm| bytes_field(rm, 2, &message(|sm| {
+            bytes_field(sm, 2, &message(|m| bytes_field(m, 5, &[])));
+        })))))), Err(OtlpError::MissingMetricName));
+        assert_eq!(decode(&[0x0a, 0x05]), Err(OtlpError::ProtobufError(ProtobufError::Truncated)));
+    }
+
+    #[test]
+    fn test_decode_json()
+    {
+        let body = br#"{"resourceMetrics": [{
+            "resource": {"attributes": [{"key": "service.name", "value": {"stringValue": "old"}},
+                                        {"key": "service.name", "value": {"stringValue": "api"}},
+                                        {"key": "pid", "value": {"intValue": "1234"}}]},
+            "scopeMetrics": [{
+                "scope": {"name": "io.example", "version": "1.2"},
+                "metrics": [
+                    {"name": "requests", "sum": {"aggregationTemporality": 1, "isMonotonic": true,
+                        "dataPoints": [{"timeUnixNano": "1567029708000000000", "asInt": "17",
+                                        "attributes": [{"key": "pid", "value": {"intValue": 1}}]}]}},
+                    {"name": "latency", "histogram": {"aggregationTemporality": 2,
+                        "dataPoints": [{"timeUnixNano": "1567029708000000000", "count": "3", "sum": 1.5,
+                                        "bucketCounts": ["1", "9223372036854775808"], "explicitBounds": [0.25]}]}},
+                    {"name": "ratio", "gauge": {"dataPoints": [{"timeUnixNano": "1567029708000000000", "asDouble": "NaN"}]}}
+                ]}]}]}"#;
+
+        let samples = decode_json(body).unwrap();
+        assert_eq!(samples.len(), 6);
+
+        assert_eq!(samples[0].key, SeriesKey::new("requests".to_string(),
+                                                  labels(&[("service.name", "api"), ("pid", "1"), ("otel_scope_name", "io.example"),
+                                                           ("otel_scope_version", "1.2"), ("otel_temporality", "delta")])));
+        assert_eq!(samples[0].measurement, Measurement{timestamp: 1567029708000, count: 1, value: 17.0});
+
+        assert_eq!(samples[1].key.name(), "latency_bucket");
+        assert_eq!(samples[1].measurement.value, 1.0);
+        assert_eq!(samples[2].measurement.value, ((1u64 << 63) + 1) as f64);
+        assert_eq!(samples[3].measurement.value, 1.5);
+        assert_eq!(samples[4].measurement.value, 3.0);
+        assert_eq!(samples[5].key.name(), "ratio");
+        assert!(samples[5].measurement.value.is_nan());
+
+        assert!(matches!(decode_json(b"{nope"), Err(OtlpError::InvalidJson(_))));
+        assert_eq!(decode_json(br#"{"resourceMetrics": 1}"#).unwrap_err().to_string(), "invalid JSON: resourceMetrics must be an array");
+        assert!(matches!(decode_json(br#"{"resourceMetrics": [{"scopeMetrics": [{"metrics": [{"name": "h",
+                           "histogram": {"dataPoints": [{"bucketCounts": ["-1"]}]}}]}]}]}"#), Err(OtlpError::InvalidJson(_))));
+        assert_eq!(decode_json(br#"{"resourceMetrics": [{"scopeMetrics": [{"metrics": [{"gauge": {}}]}]}]}"#),
+                   Err(OtlpError::MissingMetricName));
+    }
+}
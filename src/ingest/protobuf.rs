@@ -0,0 +1,158 @@
+// Minimal reader for the protobuf wire format, enough to walk the messages of the
+// protocols we ingest without generated code.
+
+use std::cmp::min;
+use std::error;
+use std::fmt;
+
+use crate::gorilla_tsz::utils::varint;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ProtobufError {
+    Truncated,
+    InvalidVarint,
+    InvalidWireType(u8),
+    InvalidUtf8
+}
+
+impl fmt::Display for ProtobufError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtobufError::Truncated => write!(f, "message truncated"),
+            ProtobufError::InvalidVarint => write!(f, "invalid varint"),
+            ProtobufError::InvalidWireType(t) => write!(f, "invalid wire type {}", t),
+            ProtobufError::InvalidUtf8 => write!(f, "string is not valid UTF-8")
+        }
+    }
+}
+
+impl error::Error for ProtobufError {}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32)
+}
+
+impl<'a> Value<'a> {
+    pub fn as_bytes(&self) -> Result<&'a [u8], ProtobufError> {
+        match *self {
+            Value::Bytes(b) => Ok(b),
+            _ => Err(ProtobufError::InvalidWireType(2))
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&'a str, ProtobufError> {
+        std::str::from_utf8(self.as_bytes()?).map_err(|_| ProtobufError::InvalidUtf8)
+    }
+
+    // For `double` fields
+    pub fn as_f64(&self) -> Result<f64, ProtobufError> {
+        match *self {
+            Value::Fixed64(bits) => Ok(f64::from_bits(bits)),
+            _ => Err(ProtobufError::InvalidWireType(1))
+        }
+    }
+
+    // For `fixed64` and `sfixed64` fields
+    pub fn as_fixed64(&self) -> Result<u64, ProtobufError> {
+        match *self {
+            Value::Fixed64(v) => Ok(v),
+            _ => Err(ProtobufError::InvalidWireType(1))
+        }
+    }
+
+    // For `int64`, `uint64`, `bool` and enum fields
+    pub fn as_varint(&self) -> Result<u64, ProtobufError> {
+        match *self {
+            Value::Varint(v) => Ok(v),
+            _ => Err(ProtobufError::InvalidWireType(0))
+        }
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    fn read_varint(&mut self) -> Result<u64, ProtobufError> {
+        let end = min(self.buf.len(), self.pos + 10);
+        let (value, sz) = varint::decode(&self.buf[self.pos..end]).map_err(|_| {
+            if end == self.buf.len() { ProtobufError::Truncated } else { ProtobufError::InvalidVarint }
+        })?;
+
+        self.pos += sz;
+        Ok(value)
+    }
+
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8], ProtobufError> {
+        let slice = self.buf.get(self.pos..self.pos.saturating_add(len)).ok_or(ProtobufError::Truncated)?;
+
+        self.pos += len;
+        Ok(slice)
+    }
+
+    // Returns the next field number and its value, or None at the end of the message
+    pub fn next_field(&mut self) -> Result<Option<(u64, Value<'a>)>, ProtobufError> {
+        if self.pos >= self.buf.len() {
+            return Ok(None);
+        }
+
+        let key = self.read_varint()?;
+
+        let value = match (key & 0x07) as u8 {
+            0 => Value::Varint(self.read_varint()?),
+            1 => {
+                let bytes = self.read_slice(8)?;
+                Value::Fixed64(u64::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3],
+                                                   bytes[4], bytes[5], bytes[6], bytes[7]]))
+            },
+            2 => {
+                let len = self.read_varint()?;
+                Value::Bytes(self.read_slice(len as usize)?)
+            },
+            5 => {
+                let bytes = self.read_slice(4)?;
+                Value::Fixed32(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            },
+            wire_type => return Err(ProtobufError::InvalidWireType(wire_type))
+        };
+
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader()
+    {
+        // 1: varint 150, 2: "hi", 3: double 1.5, 4: fixed32 7
+        let mut buf = vec![0x08, 0x96, 0x01, 0x12, 0x02, b'h', b'i', 0x19];
+        buf.extend_from_slice(&1.5f64.to_le_bytes());
+        buf.extend_from_slice(&[0x25, 7, 0, 0, 0]);
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.next_field(), Ok(Some((1, Value::Varint(150)))));
+        assert_eq!(reader.next_field().unwrap().unwrap().1.as_str(), Ok("hi"));
+        assert_eq!(reader.next_field().unwrap().unwrap().1.as_f64(), Ok(1.5));
+        assert_eq!(reader.next_field(), Ok(Some((4, Value::Fixed32(7)))));
+        assert_eq!(reader.next_field(), Ok(None));
+
+        assert_eq!(Reader::new(&[0x12, 0x05, b'a']).next_field(), Err(ProtobufError::Truncated));
+        assert_eq!(Reader::new(&[0x08, 0x80]).next_field(), Err(ProtobufError::Truncated));
+        assert_eq!(Reader::new(&[0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]).next_field(),
+                   Err(ProtobufError::InvalidVarint));
+        assert_eq!(Reader::new(&[0x0b]).next_field(), Err(ProtobufError::InvalidWireType(3)));
+    }
+}
@@ -0,0 +1,226 @@
+// Decoding of Prometheus remote write requests: a snappy compressed protobuf WriteRequest.
+// See https://prometheus.io/docs/concepts/remote_write_spec/
+
+use std::error;
+use std::fmt;
+
+use crate::gorilla_tsz::Measurement;
+use super::{SeriesKey, Sample};
+use super::protobuf::{Reader, ProtobufError};
+use super::snappy;
+use super::snappy::SnappyError;
+
+// Value Prometheus writes to mark a series as stale. It must be stored bit for bit, since
+// it's only told apart from other NaNs by its payload.
+pub const STALE_NAN: u64 = 0x7ff0000000000002;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum RemoteWriteError {
+    SnappyError(SnappyError),
+    ProtobufError(ProtobufError),
+    MissingMetricName,
+    NegativeTimestamp(i64)
+}
+
+impl fmt::Display for RemoteWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemoteWriteError::SnappyError(e) => write!(f, "snappy: {}", e),
+            RemoteWriteError::ProtobufError(e) => write!(f, "protobuf: {}", e),
+            RemoteWriteError::MissingMetricName => write!(f, "series has no __name__ label"),
+            RemoteWriteError::NegativeTimestamp(ts) => write!(f, "negative timestamp: {}", ts)
+        }
+    }
+}
+
+impl error::Error for RemoteWriteError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RemoteWriteError::SnappyError(e) => Some(e),
+            RemoteWriteError::ProtobufError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<SnappyError> for RemoteWriteError {
+    fn from(e: SnappyError) -> Self {
+        RemoteWriteError::SnappyError(e)
+    }
+}
+
+impl From<ProtobufError> for RemoteWriteError {
+    fn from(e: ProtobufError) -> Self {
+        RemoteWriteError::ProtobufError(e)
+    }
+}
+
+// message Label { string name = 1; string value = 2; }
+fn decode_label(buf: &[u8]) -> Result<(String, String), RemoteWriteError>
+{
+    let mut reader = Reader::new(buf);
+    let mut name = "";
+    let mut value = "";
+
+    while let Some((field, v)) = reader.next_field()? {
+        match field {
+            1 => name = v.as_str()?,
+            2 => value = v.as_str()?,
+            _ => {}
+        }
+    }
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+// message Sample { double value = 1; int64 timestamp = 2; }
+fn decode_sample(buf: &[u8]) -> Result<Measurement, RemoteWriteError>
+{
+    let mut reader = Reader::new(buf);
+    let mut measurement = Measurement { timestamp: 0, count: 1, value: 0.0 };
+
+    while let Some((field, v)) = reader.next_field()? {
+        match field {
+            1 => measurement.value = v.as_f64()?,
+            2 => {
+                let timestamp = v.as_varint()? as i64;
+                if timestamp < 0 {
+                    return Err(RemoteWriteError::NegativeTimestamp(timestamp));
+                }
+                measurement.timestamp = timestamp as u64;
+            },
+            _ => {}
+        }
+    }
+
+    Ok(measurement)
+}
+
+// message TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; ... }
+//
+// Exemplars and native histograms are ignored.
+fn decode_timeseries(buf: &[u8], samples: &mut Vec<Sample>) -> Result<(), RemoteWriteError>
+{
+    let mut reader = Reader::new(buf);
+    let mut name = None;
+    let mut labels = Vec::new();
+    let mut measurements = Vec::new();
+
+    while let Some((field, v)) = reader.next_field()? {
+        match field {
+            1 => {
+                let (label_name, label_value) = decode_label(v.as_bytes()?)?;
+
+                if label_name == "__name__" {
+                    name = Some(label_value);
+                } else {
+                    labels.push((label_name, label_value));
+                }
+            },
+            2 => measurements.push(decode_sample(v.as_bytes()?)?),
+            _ => {}
+        }
+    }
+
+    let key = SeriesKey::new(name.ok_or(RemoteWriteError::MissingMetricName)?, labels);
+
+    samples.extend(measurements.into_iter().map(|measurement| Sample { key: key.clone(), measurement }));
+    Ok(())
+}
+
+// Decodes an uncompressed WriteRequest. Timestamps are already in milliseconds.
+pub fn decode_write_request(buf: &[u8]) -> Result<Vec<Sample>, RemoteWriteError>
+{
+    let mut reader = Reader::new(buf);
+    let mut samples = Vec::new();
+
+    // message WriteRequest { repeated TimeSeries timeseries = 1; ... }
+    while let Some((field, v)) = reader.next_field()? {
+        if field == 1 {
+            decode_timeseries(v.as_bytes()?, &mut samples)?;
+        }
+    }
+
+    Ok(samples)
+}
+
+// Decodes a remote write HTTP request body
+pub fn decode(body: &[u8]) -> Result<Vec<Sample>, RemoteWriteError>
+{
+    decode_write_request(&snappy::decompress(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::{bytes_field, fixed64_field, key, message, varint_bytes, varint_field};
+
+    fn timeseries(labels: &[(&str, &str)], samples: &[(i64, u64)]) -> Vec<u8> {
+        message(|ts| {
+            for (name, value) in labels {
+                bytes_field(ts, 1, &message(|label| {
+                    bytes_field(label, 1, name.as_bytes());
+                    bytes_field(label, 2, value.as_bytes());
+                }));
+            }
+
+            for &(timestamp, value_bits) in samples {
+                bytes_field(ts, 2, &message(|sample| {
+                    fixed64_field(sample, 1, value_bits);
+                    varint_field(sample, 2, timestamp as u64);
+                }));
+            }
+        })
+    }
+
+    // Snappy encoding using only literals, which is valid if not very compact
+    fn snappy_literal(input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        varint_bytes(&mut out, input.len() as u64);
+
+        for chunk in input.chunks(256) {
+            out.push(60 << 2);
+            out.push((chunk.len() - 1) as u8);
+            out.extend_from_slice(chunk);
+        }
+
+        out
+    }
+
+    #[test]
+    fn test_decode()
+    {
+        let mut request = Vec::new();
+        bytes_field(&mut request, 1, &timeseries(&[("__name__", "up"), ("job", "node"), ("instance", "a:9100")],
+                                                 &[(1567029708000, 1.0f64.to_bits()), (1567029723000, STALE_NAN)]));
+        bytes_field(&mut request, 1, &timeseries(&[("__name__", "temp")], &[(1567029708000, 0x7ff8_0000_dead_beef)]));
+
+        let samples = decode(&snappy_literal(&request)).unwrap();
+        assert_eq!(samples.len(), 3);
+
+        assert_eq!(samples[0].key, key("up", &[("job", "node"), ("instance", "a:9100")]));
+        assert_eq!(samples[0].measurement, Measurement{timestamp: 1567029708000, count: 1, value: 1.0});
+        assert_eq!(samples[1].measurement.timestamp, 1567029723000);
+        assert_eq!(samples[1].measurement.value.to_bits(), STALE_NAN);
+        assert_eq!(samples[2].key.name(), "temp");
+        assert_eq!(samples[2].measurement.value.to_bits(), 0x7ff8_0000_dead_beef);
+    }
+
+    #[test]
+    fn test_decode_errors()
+    {
+        let mut request = Vec::new();
+        bytes_field(&mut request, 1, &timeseries(&[("job", "node")], &[(1, 0)]));
+        assert_eq!(decode_write_request(&request), Err(RemoteWriteError::MissingMetricName));
+
+        let mut request = Vec::new();
+        bytes_field(&mut request, 1, &timeseries(&[("__name__", "up")], &[(-5, 0)]));
+        assert_eq!(decode_write_request(&request), Err(RemoteWriteError::NegativeTimestamp(-5)));
+
+        assert_eq!(decode_write_request(&[0x0a, 0x05, 0x0a]), Err(RemoteWriteError::ProtobufError(ProtobufError::Truncated)));
+        assert_eq!(decode(&[0x05, 0x00]), Err(RemoteWriteError::SnappyError(SnappyError::Truncated)));
+
+        assert_eq!(RemoteWriteError::SnappyError(SnappyError::Truncated).to_string(), "snappy: input truncated");
+        assert_eq!(RemoteWriteError::ProtobufError(ProtobufError::InvalidWireType(6)).to_string(), "protobuf: invalid wire type 6");
+    }
+}
@@ -0,0 +1,135 @@
+// Decompression of the raw (unframed) snappy block format, as used by Prometheus remote
+// write. See https://github.com/google/snappy/blob/main/format_description.txt
+
+use std::cmp::min;
+use std::error;
+use std::fmt;
+
+use crate::gorilla_tsz::utils::varint;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum SnappyError {
+    InvalidLength,
+    Truncated,
+    InvalidOffset,
+    LengthMismatch
+}
+
+impl fmt::Display for SnappyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnappyError::InvalidLength => write!(f, "invalid uncompressed length"),
+            SnappyError::Truncated => write!(f, "input truncated"),
+            SnappyError::InvalidOffset => write!(f, "copy offset out of range"),
+            SnappyError::LengthMismatch => write!(f, "uncompressed length mismatch")
+        }
+    }
+}
+
+impl error::Error for SnappyError {}
+
+fn read_le(input: &[u8], pos: usize, nbytes: usize) -> Result<usize, SnappyError>
+{
+    let bytes = input.get(pos..pos + nbytes).ok_or(SnappyError::Truncated)?;
+
+    Ok(bytes.iter().rev().fold(0usize, |acc, &b| (acc << 8) | b as usize))
+}
+
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>, SnappyError>
+{
+    let (len, mut pos) = varint::decode(&input[..min(input.len(), 5)]).map_err(|_| SnappyError::InvalidLength)?;
+
+    if len > u32::MAX as u64 {
+        return Err(SnappyError::InvalidLength);
+    }
+
+    let len = len as usize;
+
+    // Don't trust the preamble for the allocation, it's only checked at the end
+    let mut out: Vec<u8> = Vec::with_capacity(min(len, input.len() * 8));
+
+    while pos < input.len() {
+        let tag = input[pos] as usize;
+        pos += 1;
+
+        let (copy_len, offset) = match tag & 0x03 {
+            0 => {
+                let mut lit_len = tag >> 2;
+                if lit_len >= 60 {
+                    let nbytes = lit_len - 59;
+                    lit_len = read_le(input, pos, nbytes)?;
+                    pos += nbytes;
+                }
+                lit_len += 1;
+
+                let literal = input.get(pos..pos + lit_len).ok_or(SnappyError::Truncated)?;
+                out.extend_from_slice(literal);
+                pos += lit_len;
+
+                if out.len() > len {
+                    return Err(SnappyError::LengthMismatch);
+                }
+                continue;
+            },
+            1 => {
+                let offset = ((tag >> 5) << 8) | read_le(input, pos, 1)?;
+                pos += 1;
+                (4 + ((tag >> 2) & 0x07), offset)
+            },
+            2 => {
+                let offset = read_le(input, pos, 2)?;
+                pos += 2;
+                (1 + (tag >> 2), offset)
+            },
+            _ => {
+                let offset = read_le(input, pos, 4)?;
+                pos += 4;
+                (1 + (tag >> 2), offset)
+            }
+        };
+
+        if offset == 0 || offset > out.len() {
+            return Err(SnappyError::InvalidOffset);
+        }
+
+        if out.len() + copy_len > len {
+            return Err(SnappyError::LengthMismatch);
+        }
+
+        // Copies may overlap their own output, so go a byte at a time
+        let start = out.len() - offset;
+        for i in 0..copy_len {
+            let byte = out[start + i];
+            out.push(byte);
+        }
+    }
+
+    if out.len() != len {
+        return Err(SnappyError::LengthMismatch);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress()
+    {
+        // Literal "abc" followed by an overlapping 1-byte-offset copy of 9 bytes
+        assert_eq!(decompress(&[12, 0x08, b'a', b'b', b'c', 0x15, 3]), Ok(b"abcabcabcabc".to_vec()));
+
+        // Long literal with a 1 byte length
+        let mut input = vec![100, 60 << 2, 99];
+        input.extend_from_slice(&[7u8; 100]);
+        assert_eq!(decompress(&input), Ok(vec![7u8; 100]));
+
+        assert_eq!(decompress(&[]), Err(SnappyError::InvalidLength));
+        assert_eq!(decompress(&[4, 0x08, b'a']), Err(SnappyError::Truncated));
+        assert_eq!(decompress(&[4, 0x00, b'a', 0x15, 2]), Err(SnappyError::InvalidOffset));
+        assert_eq!(decompress(&[2, 0x08, b'a', b'b', b'c']), Err(SnappyError::LengthMismatch));
+        assert_eq!(decompress(&[5, 0x08, b'a', b'b', b'c']), Err(SnappyError::LengthMismatch));
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::SyncSender;
+use std::time::{Duration, Instant};
+
+use crate::gorilla_tsz::Measurement;
+use super::{SeriesKey, Sample, Counters, now_millis};
+
+#[derive(Debug, PartialEq)]
+pub enum Update {
+    Counter { value: f64, sample_rate: f64 },
+    // Gauges prefixed with + or - adjust the current value
+    Gauge { value: f64, relative: bool },
+    Timer { value: f64, sample_rate: f64 },
+    Set(String)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Metric {
+    pub key: SeriesKey,
+    pub update: Update
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseError {
+    MissingValue,
+    MissingType,
+    InvalidName(String),
+    InvalidValue(String),
+    InvalidType(String),
+    InvalidSampleRate(String)
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::MissingValue => write!(f, "missing value"),
+            ParseError::MissingType => write!(f, "missing type"),
+            ParseError::InvalidName(n) => write!(f, "invalid name: {}", n),
+            ParseError::InvalidValue(v) => write!(f, "invalid value: {}", v),
+            ParseError::InvalidType(t) => write!(f, "invalid type: {}", t),
+            ParseError::InvalidSampleRate(r) => write!(f, "invalid sample rate: {}", r)
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+// Parses one `<name>:<value>|<type>[|@<rate>][|#<tag>:<value>,...]` line. Histograms (`h`)
+// are handled as timers. DogStatsD style tags become labels.
+pub fn parse_line(line: &str) -> Result<Metric, ParseError>
+{
+    let (name, rest) = line.split_once(':').ok_or(ParseError::MissingValue)?;
+
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(ParseError::InvalidName(name.to_string()));
+    }
+
+    let mut parts = rest.split('|');
+    let value = parts.next().unwrap_or_default();
+    let kind = parts.next().ok_or(ParseError::MissingType)?;
+
+    let mut sample_rate = 1.0;
+    let mut labels = Vec::new();
+
+    for part in parts {
+        if let Some(rate) = part.strip_prefix('@') {
+            sample_rate = match rate.parse::<f64>() {
+                Ok(r) if r > 0.0 && r <= 1.0 => r,
+                _ => return Err(ParseError::InvalidSampleRate(rate.to_string()))
+            };
+        } else if let Some(tags) = part.strip_prefix('#') {
+            for tag in tags.split(',').filter(|t| !t.is_empty()) {
+                let (k, v) = tag.split_once(':').unwrap_or((tag, ""));
+                labels.push((k.to_string(), v.to_string()));
+            }
+        }
+    }
+
+    let parse_value = || value.parse::<f64>().map_err(|_| ParseError::InvalidValue(value.to_string()));
+
+    let update = match kind {
+        "c" => Update::Counter { value: parse_value()?, sample_rate },
+        "g" => Update::Gauge { value: parse_value()?, relative: value.starts_with('+') || value.starts_with('-') },
+        "ms" | "h" => Update::Timer { value: parse_value()?, sample_rate },
+        "s" => Update::Set(value.to_string()),
+        _ => return Err(ParseError::InvalidType(kind.to_string()))
+    };
+
+    Ok(Metric { key: SeriesKey::new(name.to_string(), labels), update })
+}
+
+#[derive(Default)]
+struct Timer {
+    values: Vec<f64>,
+    // Scaled up by the sample rate
+    count: f64
+}
+
+// Aggregates updates over one flush interval. Measurement::count on every flushed sample
+// is the number of updates received for it during the interval.
+pub struct Aggregator {
+    percentiles: Vec<f64>,
+    counters: HashMap<SeriesKey, (f64, u64)>,
+    // Gauges keep their value across flushes so relative updates have a base, but are
+    // only flushed when updated during the interval
+    gauges: HashMap<SeriesKey, (f64, u64)>,
+    timers: HashMap<SeriesKey, Timer>,
+    sets: HashMap<SeriesKey, (HashSet<String>, u64)>
+}
+
+impl Aggregator {
+    // `percentiles` are the timer percentiles to compute, eg. 90.0 or 99.9
+    pub fn new(percentiles: Vec<f64>) -> Aggregator {
+        Aggregator {
+            percentiles,
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            timers: HashMap::new(),
+            sets: HashMap::new()
+        }
+    }
+
+    pub fn add(&mut self, metric: Metric) {
+        match metric.update {
+            Update::Counter { value, sample_rate } => {
+                let counter = self.counters.entry(metric.key).or_insert((0.0, 0));
+                counter.0 += value / sample_rate;
+                counter.1 += 1;
+            },
+            Update::Gauge { value, relative } => {
+                let gauge = self.gauges.entry(metric.key).or_insert((0.0, 0));
+                gauge.0 = if relative { gauge.0 + value } else { value };
+                gauge.1 += 1;
+            },
+            Update::Timer { value, sample_rate } => {
+                let timer = self.timers.entry(metric.key).or_default();
+                timer.values.push(value);
+                timer.count += 1.0 / sample_rate;
+            },
+            Update::Set(member) => {
+                let set = self.sets.entry(metric.key).or_insert((HashSet::new(), 0));
+                set.0.insert(member);
+                set.1 += 1;
+            }
+        }
+    }
+
+    // Emits the aggregated interval at `timestamp` and resets for the next one. Counters
+    // and sets keep their name, timers are expanded into `<name>_<stat>` series.
+    pub fn flush(&mut self, timestamp: u64) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        let mut push = |key: SeriesKey, count: u64, value: f64| {
+            samples.push(Sample { key, measurement: Measurement { timestamp, count, value } });
+        };
+
+        for (key, (value, count)) in self.counters.drain() {
+            push(key, count, value);
+        }
+
+        for (key, gauge) in self.gauges.iter_mut().filter(|(_, g)| g.1 > 0) {
+            push(key.clone(), gauge.1, gauge.0);
+            gauge.1 = 0;
+        }
+
+        for (key, (members, count)) in self.sets.drain() {
+            push(key, count, members.len() as f64);
+        }
+
+        for (key, mut timer) in self.timers.drain() {
+            timer.values.sort_by(f64::total_cmp);
+
+            let values = &timer.values;
+            let count = values.len() as u64;
+            let sum: f64 = values.iter().sum();
+            let stat = |name: &str| SeriesKey::new(format!("{}_{}", key.name(), name), key.labels().to_vec());
+
+            push(stat("count"), count, timer.count);
+            push(stat("sum"), count, sum);
+            push(stat("mean"), count, sum / values.len() as f64);
+            push(stat("lower"), count, values[0]);
+            push(stat("upper"), count, values[values.len() - 1]);
+
+            for pct in &self.percentiles {
+                // Nearest rank
+                let rank = (pct / 100.0 * values.len() as f64).ceil() as usize;
+                let value = values[rank.clamp(1, values.len()) - 1];
+
+                push(stat(&format!("p{}", pct.to_string().replace('.', "_"))), count, value);
+            }
+        }
+
+        samples
+    }
+}
+
+// Receives StatsD packets on `socket` forever, sending every aggregated sample to `sink`
+// once per `flush_interval`. As with Graphite, a full sink blocks the listener.
+pub fn serve(socket: UdpSocket, flush_interval: Duration, percentiles: Vec<f64>,
+             sink: SyncSender<Sample>, counters: Arc<Counters>) -> io::Result<()>
+{
+    let mut aggregator = Aggregator::new(percentiles);
+    let mut buf = [0u8; 65535];
+    let mut next_flush = Instant::now() + flush_interval;
+
+    loop {
+        let now = Instant::now();
+
+        if now >= next_flush {
+            for sample in aggregator.flush(now_millis()) {
+                if sink.send(sample).is_err() {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "sample receiver closed"));
+                }
+            }
+
+            next_flush += flush_interval;
+            continue;
+        }
+
+        socket.set_read_timeout(Some(next_flush - now))?;
+
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e)
+        };
+
+        for line in String::from_utf8_lossy(&buf[..len]).lines().map(str::trim).filter(|l| !l.is_empty()) {
+            match parse_line(line) {
+                Ok(metric) => {
+                    counters.received.fetch_add(1, Ordering::Relaxed);
+                    aggregator.add(metric);
+                },
+                Err(_) => {
+                    counters.malformed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+    use std::thread;
+
+    fn key(name: &str) -> SeriesKey {
+        SeriesKey::new(name.to_string(), Vec::new())
+    }
+
+    fn flushed(aggregator: &mut Aggregator) -> Vec<(String, u64, f64)> {
+        let mut samples: Vec<(String, u64, f64)> = aggregator.flush(1000).into_iter()
+            .map(|s| (s.key.name().to_string(), s.measurement.count, s.measurement.value))
+            .collect();
+
+        samples.sort_by(|a, b| a.0.cmp(&b.0));
+        samples
+    }
+
+    #[test]
+    fn test_parse_line()
+    {
+        assert_eq!(parse_line("hits:1|c"), Ok(Metric{key: key("hits"), update: Update::Counter{value: 1.0, sample_rate: 1.0}}));
+        assert_eq!(parse_line("hits:2|c|@0.1").unwrap().update, Update::Counter{value: 2.0, sample_rate: 0.1});
+        assert_eq!(parse_line("temp:-3|g").unwrap().update, Update::Gauge{value: -3.0, relative: true});
+        assert_eq!(parse_line("latency:320|ms").unwrap().update, Update::Timer{value: 320.0, sample_rate: 1.0});
+        assert_eq!(parse_line("size:12|h").unwrap().update, Update::Timer{value: 12.0, sample_rate: 1.0});
+        assert_eq!(parse_line("users:alice|s").unwrap().update, Update::Set("alice".to_string()));
+
+        let metric = parse_line("req:1|c|#env:prod,canary").unwrap();
+        assert_eq!(metric.key, SeriesKey::new("req".to_string(),
+                                              vec![("env".to_string(), "prod".to_string()), ("canary".to_string(), String::new())]));
+
+        assert_eq!(parse_line("hits"), Err(ParseError::MissingValue));
+        assert_eq!(parse_line("hits:1"), Err(ParseError::MissingType));
+        assert_eq!(parse_line("hits:x|c"), Err(ParseError::InvalidValue("x".to_string())));
+        assert_eq!(parse_line("hits:1|q"), Err(ParseError::InvalidType("q".to_string())));
+        assert_eq!(parse_line("hits:1|c|@2"), Err(ParseError::InvalidSampleRate("2".to_string())));
+        assert_eq!(parse_line("hits:1|q").unwrap_err().to_string(), "invalid type: q");
+    }
+
+    #[test]
+    fn test_aggregate()
+    {
+        let mut aggregator = Aggregator::new(vec![50.0, 90.0, 99.9]);
+
+        for line in &["hits:1|c", "hits:2|c|@0.5", "temp:20|g", "temp:+5|g", "users:a|s", "users:b|s", "users:a|s"] {
+            aggregator.add(parse_line(line).unwrap());
+        }
+        for i in 1..=10 {
+            aggregator.add(parse_line(&format!("rt:{}|ms|@0.5", i * 10)).unwrap());
+        }
+
+        assert_eq!(flushed(&mut aggregator), vec![
+            ("hits".to_string(), 2, 5.0),
+            ("rt_count".to_string(), 10, 20.0),
+            ("rt_lower".to_string(), 10, 10.0),
+            ("rt_mean".to_string(), 10, 55.0),
+            ("rt_p50".to_string(), 10, 50.0),
+            ("rt_p90".to_string(), 10, 90.0),
+            ("rt_p99_9".to_string(), 10, 100.0),
+            ("rt_sum".to_string(), 10, 550.0),
+            ("rt_upper".to_string(), 10, 100.0),
+            ("temp".to_string(), 2, 25.0),
+            ("users".to_string(), 3, 2.0)
+        ]);
+
+        // Only gauges survive a flush, and only as the base for relative updates
+        assert_eq!(flushed(&mut aggregator), vec![]);
+
+        aggregator.add(parse_line("temp:-1|g").unwrap());
+        assert_eq!(flushed(&mut aggregator), vec![("temp".to_string(), 1, 24.0)]);
+    }
+
+    #[test]
+    fn test_serve()
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let counters = Arc::new(Counters::default());
+        let (tx, rx) = sync_channel(16);
+
+        let serve_counters = counters.clone();
+        thread::spawn(move || serve(socket, Duration::from_millis(200), Vec::new(), tx, serve_counters));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"jobs:1|c\njobs:4|c\nbogus\n", addr).unwrap();
+
+        let sample = rx.recv().unwrap();
+        assert_eq!(sample.key, key("jobs"));
+        assert_eq!((sample.measurement.count, sample.measurement.value), (2, 5.0));
+        assert_eq!(counters.malformed.load(Ordering::Relaxed), 1);
+    }
+}
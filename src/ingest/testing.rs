@@ -0,0 +1,48 @@
+// Builders shared by the ingest and bulk tests: protobuf messages written field by field,
+// and series keys from string pairs.
+
+use crate::gorilla_tsz::utils::varint;
+use super::SeriesKey;
+
+pub(crate) fn varint_bytes(buf: &mut Vec<u8>, value: u64)
+{
+    let mut tmp = [0u8; 10];
+    let sz = varint::encode(value, &mut tmp).unwrap();
+    buf.extend_from_slice(&tmp[..sz]);
+}
+
+pub(crate) fn bytes_field(buf: &mut Vec<u8>, num: u64, bytes: &[u8])
+{
+    varint_bytes(buf, (num << 3) | 2);
+    varint_bytes(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+pub(crate) fn fixed64_field(buf: &mut Vec<u8>, num: u64, value: u64)
+{
+    varint_bytes(buf, (num << 3) | 1);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn varint_field(buf: &mut Vec<u8>, num: u64, value: u64)
+{
+    varint_bytes(buf, num << 3);
+    varint_bytes(buf, value);
+}
+
+pub(crate) fn message(f: impl FnOnce(&mut Vec<u8>)) -> Vec<u8>
+{
+    let mut buf = Vec::new();
+    f(&mut buf);
+    buf
+}
+
+pub(crate) fn labels(labels: &[(&str, &str)]) -> Vec<(String, String)>
+{
+    labels.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+pub(crate) fn key(name: &str, pairs: &[(&str, &str)]) -> SeriesKey
+{
+    SeriesKey::new(name.to_string(), labels(pairs))
+}
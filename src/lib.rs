@@ -0,0 +1,6 @@
+pub mod bulk;
+pub mod cli;
+pub mod gorilla_tsz;
+pub mod ingest;
+pub mod query;
+pub mod rollup;
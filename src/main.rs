@@ -1,8 +1,18 @@
+use std::env;
+use std::io;
+use std::process;
 
-pub mod gorilla_tsz;
-
-use gorilla_tsz::utils::varint;
+use gorilla_tsdb::cli;
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(e) = cli::run(&args, &mut io::stdout().lock()) {
+        eprintln!("error: {}", e);
+
+        process::exit(match e {
+            cli::CliError::Usage(_) => 2,
+            _ => 1
+        });
+    }
 }
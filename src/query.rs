@@ -0,0 +1,282 @@
+// Step aggregation over the measurements of one series. Picking the series is the caller's
+// job: there is no store to resolve a series selector against, so callers hand over the
+// measurements of the block they've already chosen, eg. by its ingest::SeriesKey.
+
+use std::error;
+use std::fmt;
+
+use crate::gorilla_tsz::Measurement;
+use crate::gorilla_tsz::codec::decoder::DecoderError;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Aggregation {
+    Sum,
+    // Weighted by Measurement::count
+    Avg,
+    Min,
+    Max,
+    // Total of Measurement::count, ie. the number of samples behind the step
+    Count,
+    First,
+    Last,
+    // Population standard deviation, weighted by Measurement::count
+    StdDev,
+    // Counter increase per timestamp unit
+    Rate,
+    // Counter increase, treating any decrease as a counter reset
+    Increase
+}
+
+// Aggregate values over [start, end), one per `step` wide window starting at `start`.
+// All three are in the same unit as the measurement timestamps.
+#[derive(Copy, Clone, Debug)]
+pub struct Query {
+    pub start: u64,
+    pub end: u64,
+    pub step: u64,
+    pub aggregation: Aggregation
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Point {
+    pub timestamp: u64,
+    pub value: f64
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+    InvalidRange,
+    // Measurements must be in timestamp order to aggregate them in a single pass
+    UnsortedInput(u64),
+    DecoderError(DecoderError)
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::InvalidRange => write!(f, "invalid query range"),
+            QueryError::UnsortedInput(timestamp) => write!(f, "measurements out of order at timestamp {}", timestamp),
+            QueryError::DecoderError(e) => write!(f, "decoding failed: {}", e)
+        }
+    }
+}
+
+impl error::Error for QueryError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            QueryError::DecoderError(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<DecoderError> for QueryError {
+    fn from(e: DecoderError) -> Self {
+        QueryError::DecoderError(e)
+    }
+}
+
+struct Accumulator {
+    first: f64,
+    last: f64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    // Total of Measurement::count, as a float so huge counts can't overflow it
+    count: f64,
+    mean: f64,
+    m2: f64,
+    increase: f64,
+    deltas: usize
+}
+
+impl Accumulator {
+    fn new(value: f64) -> Accumulator {
+        Accumulator {
+            first: value, last: value, min: value, max: value, sum: 0.0, count: 0.0,
+            mean: 0.0, m2: 0.0, increase: 0.0, deltas: 0
+        }
+    }
+
+    fn add(&mut self, measurement: &Measurement, prev_value: Option<f64>) {
+        let value = measurement.value;
+
+        self.last = value;
+        self.min = f64::min(self.min, value);
+        self.max = f64::max(self.max, value);
+        self.sum += value;
+
+        // Weighted incremental mean/variance (West, 1979)
+        if measurement.count > 0 {
+            let weight = measurement.count as f64;
+            self.count += weight;
+
+            let delta = value - self.mean;
+            self.mean += delta * weight / self.count;
+            self.m2 += weight * delta * (value - self.mean);
+        }
+
+        if let Some(prev) = prev_value {
+            self.increase += if value < prev { value } else { value - prev };
+            self.deltas += 1;
+        }
+    }
+
+    fn result(&self, aggregation: Aggregation, step: u64) -> Option<f64> {
+        match aggregation {
+            Aggregation::Sum => Some(self.sum),
+            Aggregation::Avg if self.count > 0.0 => Some(self.mean),
+            Aggregation::Min => Some(self.min),
+            Aggregation::Max => Some(self.max),
+            Aggregation::Count => Some(self.count),
+            Aggregation::First => Some(self.first),
+            Aggregation::Last => Some(self.last),
+            Aggregation::StdDev if self.count > 0.0 => Some(f64::sqrt(self.m2 / self.count)),
+            Aggregation::Rate if self.deltas > 0 => Some(self.increase / step as f64),
+            Aggregation::Increase if self.deltas > 0 => Some(self.increase),
+            _ => None
+        }
+    }
+}
+
+// Aggregates a stream of measurements, usually straight out of decoder::measurements(), into
+// one Point per step. Steps without any measurements produce no point. Measurements before
+// `start` are only used as the baseline for Rate/Increase, and reading stops at `end`.
+pub fn aggregate<I>(measurements: I, query: &Query) -> Result<Vec<Point>, QueryError>
+    where I: IntoIterator<Item = Result<Measurement, DecoderError>>
+{
+    if query.step == 0 || query.start >= query.end {
+        return Err(QueryError::InvalidRange);
+    }
+
+    let mut points = Vec::new();
+    let mut current: Option<(u64, Accumulator)> = None;
+    let mut prev: Option<Measurement> = None;
+
+    for measurement in measurements {
+        let measurement = measurement?;
+
+        if let Some(p) = prev {
+            if measurement.timestamp < p.timestamp {
+                return Err(QueryError::UnsortedInput(measurement.timestamp));
+            }
+        }
+
+        if measurement.timestamp >= query.end {
+            break;
+        }
+
+        if measurement.timestamp >= query.start {
+            let window = measurement.timestamp - (measurement.timestamp - query.start) % query.step;
+            let prev_value = prev.map(|p| p.value);
+
+            match current {
+                Some((timestamp, ref mut acc)) if timestamp == window => acc.add(&measurement, prev_value),
+                _ => {
+                    let mut acc = Accumulator::new(measurement.value);
+                    acc.add(&measurement, prev_value);
+
+                    if let Some((timestamp, finished)) = current.replace((window, acc)) {
+                        if let Some(value) = finished.result(query.aggregation, query.step) {
+                            points.push(Point { timestamp, value });
+                        }
+                    }
+                }
+            }
+        }
+
+        prev = Some(measurement);
+    }
+
+    if let Some((timestamp, finished)) = current {
+        if let Some(value) = finished.result(query.aggregation, query.step) {
+            points.push(Point { timestamp, value });
+        }
+    }
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gorilla_tsz::codec::CodecMetadata;
+    use crate::gorilla_tsz::codec::encoder::encode;
+    use crate::gorilla_tsz::codec::decoder::measurements;
+
+    fn run(input: &[(u64, u64, f64)], start: u64, end: u64, step: u64, aggregation: Aggregation) -> Vec<(u64, f64)> {
+        let input = input.iter().map(|&(timestamp, count, value)| Ok(Measurement{timestamp, count, value}));
+        let query = Query{start, end, step, aggregation};
+
+        aggregate(input, &query).unwrap().iter().map(|p| (p.timestamp, p.value)).collect()
+    }
+
+    #[test]
+    fn test_aggregations()
+    {
+        let input = [(0, 1, 2.0), (10, 1, 8.0), (20, 6, 5.0), (30, 1, 1.0), (45, 1, 3.0), (70, 1, 5.0)];
+
+        assert_eq!(run(&input, 0, 100, 30, Aggregation::Sum), vec![(0, 15.0), (30, 4.0), (60, 5.0)]);
+        assert_eq!(run(&input, 0, 100, 30, Aggregation::Avg), vec![(0, 5.0), (30, 2.0), (60, 5.0)]);
+        assert_eq!(run(&input, 0, 100, 30, Aggregation::Min), vec![(0, 2.0), (30, 1.0), (60, 5.0)]);
+        assert_eq!(run(&input, 0, 100, 30, Aggregation::Max), vec![(0, 8.0), (30, 3.0), (60, 5.0)]);
+        assert_eq!(run(&input, 0, 100, 30, Aggregation::Count), vec![(0, 8.0), (30, 2.0), (60, 1.0)]);
+        assert_eq!(run(&input, 0, 100, 30, Aggregation::First), vec![(0, 2.0), (30, 1.0), (60, 5.0)]);
+        assert_eq!(run(&input, 0, 100, 30, Aggregation::Last), vec![(0, 5.0), (30, 3.0), (60, 5.0)]);
+        assert_eq!(run(&input, 0, 100, 30, Aggregation::StdDev), vec![(0, 1.5), (30, 1.0), (60, 0.0)]);
+
+        // Range and step alignment
+        assert_eq!(run(&input, 15, 45, 10, Aggregation::Sum), vec![(15, 5.0), (25, 1.0)]);
+
+        // Counts summing past u64::MAX
+        let huge = [(0, u64::MAX, 2.0), (10, u64::MAX, 4.0)];
+        assert_eq!(run(&huge, 0, 30, 30, Aggregation::Count), vec![(0, 2.0 * u64::MAX as f64)]);
+        assert_eq!(run(&huge, 0, 30, 30, Aggregation::Avg), vec![(0, 3.0)]);
+        assert_eq!(run(&huge, 0, 30, 30, Aggregation::StdDev), vec![(0, 1.0)]);
+    }
+
+    #[test]
+    fn test_counter_aggregations()
+    {
+        // Counter reset between 30 and 40
+        let input = [(0, 1, 10.0), (10, 1, 15.0), (20, 1, 30.0), (30, 1, 40.0), (40, 1, 5.0), (50, 1, 15.0)];
+
+        assert_eq!(run(&input, 0, 60, 20, Aggregation::Increase), vec![(0, 5.0), (20, 25.0), (40, 15.0)]);
+        assert_eq!(run(&input, 0, 60, 20, Aggregation::Rate), vec![(0, 0.25), (20, 1.25), (40, 0.75)]);
+
+        // Samples before the range still serve as the baseline
+        assert_eq!(run(&input, 20, 60, 40, Aggregation::Increase), vec![(20, 40.0)]);
+    }
+
+    #[test]
+    fn test_invalid_queries()
+    {
+        let input = vec![Ok(Measurement{timestamp: 10, count: 1, value: 1.0}),
+                         Ok(Measurement{timestamp: 5, count: 1, value: 1.0})];
+
+        let query = Query{start: 0, end: 100, step: 0, aggregation: Aggregation::Sum};
+        assert!(matches!(aggregate(Vec::new(), &query), Err(QueryError::InvalidRange)));
+
+        let query = Query{start: 0, end: 100, step: 10, aggregation: Aggregation::Sum};
+        assert!(matches!(aggregate(input, &query), Err(QueryError::UnsortedInput(5))));
+    }
+
+    #[test]
+    fn test_aggregate_block()
+    {
+        let mut buf = [0u8; 4096];
+        let mut metadata = CodecMetadata::new();
+
+        for i in 0..100u64 {
+            let measure = Measurement{timestamp: 1567029600 + i * 60, count: 1, value: i as f64};
+            assert_eq!(encode(&mut buf, &mut metadata, &measure).ok(), Some(()));
+        }
+
+        let query = Query{start: 1567029600, end: 1567029600 + 3600, step: 600, aggregation: Aggregation::Avg};
+        let points = aggregate(measurements(&buf, metadata.measurement_count()), &query).unwrap();
+
+        assert_eq!(points.len(), 6);
+        assert_eq!(points[0], Point{timestamp: 1567029600, value: 4.5});
+        assert_eq!(points[5], Point{timestamp: 1567029600 + 3000, value: 54.5});
+    }
+}
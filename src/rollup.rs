@@ -0,0 +1,215 @@
+use std::error;
+use std::fmt;
+
+use crate::gorilla_tsz::Measurement;
+use crate::gorilla_tsz::codec::decoder::DecoderError;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RollupError {
+    ZeroResolution
+}
+
+impl fmt::Display for RollupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RollupError::ZeroResolution => write!(f, "rollup resolution must be non-zero")
+        }
+    }
+}
+
+impl error::Error for RollupError {}
+
+// A single lower-resolution point covering [timestamp, timestamp + resolution).
+//
+// `count` is the number of raw measurements that fell into the window. Input points that
+// are themselves rollups bring their own count, so rollups can be cascaded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rollup {
+    pub timestamp: u64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: u64
+}
+
+impl Rollup {
+    fn new(timestamp: u64, measurement: &Measurement) -> Rollup {
+        let value = measurement.value;
+        Rollup { timestamp, min: value, max: value, sum: value, count: measurement.count }
+    }
+
+    fn add(&mut self, measurement: &Measurement) {
+        self.min = f64::min(self.min, measurement.value);
+        self.max = f64::max(self.max, measurement.value);
+        self.sum += measurement.value;
+        // Counts near u64::MAX would otherwise overflow
+        self.count = self.count.saturating_add(measurement.count);
+    }
+
+    // The min, max and sum of a rollup are each stored as their own regular series, with
+    // Measurement::count carrying the number of raw samples behind the point.
+    pub fn min_measurement(&self) -> Measurement {
+        Measurement { timestamp: self.timestamp, count: self.count, value: self.min }
+    }
+
+    pub fn max_measurement(&self) -> Measurement {
+        Measurement { timestamp: self.timestamp, count: self.count, value: self.max }
+    }
+
+    pub fn sum_measurement(&self) -> Measurement {
+        Measurement { timestamp: self.timestamp, count: self.count, value: self.sum }
+    }
+}
+
+pub struct Rollups<I> {
+    measurements: I,
+    resolution: u64,
+    current: Option<Rollup>
+}
+
+impl<I> Iterator for Rollups<I>
+    where I: Iterator<Item = Result<Measurement, DecoderError>>
+{
+    type Item = Result<Rollup, DecoderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let measurement = match self.measurements.next() {
+                None => return self.current.take().map(Ok),
+                Some(Err(e)) => {
+                    self.current = None;
+                    return Some(Err(e));
+                },
+                Some(Ok(measurement)) => measurement
+            };
+
+            let window = measurement.timestamp - measurement.timestamp % self.resolution;
+
+            match self.current {
+                Some(ref mut rollup) if rollup.timestamp == window => rollup.add(&measurement),
+                _ => {
+                    let finished = self.current.replace(Rollup::new(window, &measurement));
+
+                    if finished.is_some() {
+                        return finished.map(Ok);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Aggregates full-resolution measurements into one Rollup per `resolution` wide window,
+// aligned to multiples of the resolution. Windows are emitted as soon as a measurement
+// past them is seen, so input is expected in timestamp order (as decoded from a block).
+//
+// `resolution` is in the same unit as the measurement timestamps.
+pub fn rollup<I>(measurements: I, resolution: u64) -> Result<Rollups<I::IntoIter>, RollupError>
+    where I: IntoIterator<Item = Result<Measurement, DecoderError>>
+{
+    if resolution == 0 {
+        return Err(RollupError::ZeroResolution);
+    }
+
+    Ok(Rollups { measurements: measurements.into_iter(), resolution, current: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gorilla_tsz::codec::CodecMetadata;
+    use crate::gorilla_tsz::codec::encoder::encode;
+    use crate::gorilla_tsz::codec::decoder::measurements;
+
+    #[test]
+    fn test_rollup_block()
+    {
+        let mut buf = [0u8; 4096];
+        let mut metadata = CodecMetadata::new();
+
+        // Two hours of 10s data
+        for i in 0..720u64 {
+            let measure = Measurement{timestamp: 1567029600 + i * 10, count: 1, value: (i % 60) as f64};
+            assert_eq!(encode(&mut buf, &mut metadata, &measure).ok(), Some(()));
+        }
+
+        let rollups: Vec<Rollup> = rollup(measurements(&buf, metadata.measurement_count()), 300).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+
+        assert_eq!(rollups.len(), 24);
+        assert_eq!(rollups[0], Rollup{timestamp: 1567029600, min: 0.0, max: 29.0, sum: 435.0, count: 30});
+        assert_eq!(rollups[1], Rollup{timestamp: 1567029900, min: 30.0, max: 59.0, sum: 1335.0, count: 30});
+
+        // Rollups are stored as regular blocks
+        let mut sum_buf = [0u8; 1024];
+        let mut sum_metadata = CodecMetadata::new();
+        for r in &rollups {
+            assert_eq!(encode(&mut sum_buf, &mut sum_metadata, &r.sum_measurement()).ok(), Some(()));
+        }
+
+        for (r, decoded) in rollups.iter().zip(measurements(&sum_buf, sum_metadata.measurement_count())) {
+            let decoded = decoded.unwrap();
+
+            assert_eq!(decoded.timestamp, r.timestamp);
+            assert_eq!(decoded.count, r.count);
+            assert_eq!(decoded.value, r.sum);
+        }
+    }
+
+    #[test]
+    fn test_rollup_unaligned_gaps()
+    {
+        let input = vec![
+            Measurement{timestamp: 65, count: 1, value: 2.0},
+            Measurement{timestamp: 119, count: 1, value: -1.0},
+            Measurement{timestamp: 400, count: 1, value: 7.5}
+        ];
+
+        let rollups: Vec<Rollup> = rollup(input.into_iter().map(Ok), 60).unwrap().map(|r| r.unwrap()).collect();
+
+        assert_eq!(rollups, vec![
+            Rollup{timestamp: 60, min: -1.0, max: 2.0, sum: 1.0, count: 2},
+            Rollup{timestamp: 360, min: 7.5, max: 7.5, sum: 7.5, count: 1}
+        ]);
+
+        assert_eq!(rollup(Vec::new(), 0).err(), Some(RollupError::ZeroResolution));
+
+        let huge = vec![Measurement{timestamp: 0, count: u64::MAX, value: 1.0}, Measurement{timestamp: 1, count: 2, value: 1.0}];
+        let rollups: Vec<Rollup> = rollup(huge.into_iter().map(Ok), 60).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(rollups[0].count, u64::MAX);
+    }
+
+    #[test]
+    fn test_rollup_cascade()
+    {
+        // An hour of 10s data, with some raw points already counting several samples
+        let raw: Vec<Measurement> = (0..360u64)
+            .map(|i| Measurement{timestamp: 1567029600 + i * 10, count: 1 + i % 3, value: ((i * 7) % 23) as f64})
+            .collect();
+
+        let collect = |input: Vec<Measurement>, resolution| -> Vec<Rollup> {
+            rollup(input.into_iter().map(Ok), resolution).unwrap().map(|r| r.unwrap()).collect()
+        };
+
+        let direct = collect(raw.clone(), 3600);
+        let minutes = collect(raw, 60);
+        let fives = |series: fn(&Rollup) -> Measurement| collect(minutes.iter().map(series).collect(), 300);
+
+        // 1m -> 5m -> 1h, each level rolled up from the series below it
+        let (mins, maxes, sums) = (fives(Rollup::min_measurement), fives(Rollup::max_measurement), fives(Rollup::sum_measurement));
+        let hour_min = collect(mins.iter().map(Rollup::min_measurement).collect(), 3600);
+        let hour_max = collect(maxes.iter().map(Rollup::max_measurement).collect(), 3600);
+        let hour_sum = collect(sums.iter().map(Rollup::sum_measurement).collect(), 3600);
+
+        assert_eq!(direct.len(), 1);
+        assert_eq!(direct[0].count, 720);
+        assert_eq!((hour_min[0].min, hour_max[0].max, hour_sum[0].sum), (direct[0].min, direct[0].max, direct[0].sum));
+
+        // Every level counts raw samples, not the points below it
+        for cascaded in [&hour_min[0], &hour_max[0], &hour_sum[0]] {
+            assert_eq!(cascaded.count, 720);
+        }
+        assert!(sums.iter().all(|r| r.count == 60));
+    }
+}